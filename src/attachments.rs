@@ -0,0 +1,57 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+/// Content of the attachments as files named by their sha256, so the same file is stored once
+pub(crate) struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Store the content of the file, returns its hash and size
+    pub fn put(&self, path: &Path) -> Result<(String, i64)> {
+        let content =
+            fs::read(path).map_err(|err| anyhow!("Could not read {}: {err}", path.display()))?;
+        self.put_content(&content)
+    }
+
+    /// Store the content, returns its hash and size
+    pub fn put_content(&self, content: &[u8]) -> Result<(String, i64)> {
+        let hash = format!("{:x}", Sha256::digest(content));
+        fs::create_dir_all(&self.dir)?;
+        let blob = self.dir.join(&hash);
+        if !blob.exists() {
+            fs::write(&blob, content)?;
+        }
+        Ok((hash, content.len() as i64))
+    }
+
+    /// Path of the stored content with the given hash
+    pub fn get(&self, hash: &str) -> Result<PathBuf> {
+        let blob = self.dir.join(hash);
+        if !blob.exists() {
+            return Err(anyhow!("The content of the attachment {hash} is missing"));
+        }
+        Ok(blob)
+    }
+
+    /// Copy the stored content of the hashes into another directory, skipping what is there already
+    pub fn copy_to(&self, hashes: &[&str], dir: &Path) -> Result<usize> {
+        fs::create_dir_all(dir)?;
+        let mut copied = 0;
+        for hash in hashes {
+            let target = dir.join(hash);
+            if !target.exists() {
+                fs::copy(self.get(hash)?, target)?;
+                copied += 1;
+            }
+        }
+        Ok(copied)
+    }
+}
@@ -1,49 +1,254 @@
 use std::fmt::Debug;
+use std::ops::{Deref, DerefMut};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use sqlx::FromRow;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use sqlx::error::ErrorKind;
+use sqlx::pool::PoolConnection;
+use sqlx::{Database, FromRow, Pool, Transaction};
+use tokio::sync::{Mutex, MutexGuard};
 
+use crate::encrypted::SECRET;
 use crate::{config::Config, empty_or_value};
 
 #[async_trait]
 pub trait Initable {
     fn initialized(&self) -> bool;
-    async fn init(&mut self) -> Result<()>;
+    async fn init(&self) -> Result<()>;
 }
 
 #[async_trait]
 pub trait ProjectRepository: Initable {
-    async fn create_proj_table(&mut self) -> Result<()>;
-    async fn insert_project(&mut self, entity: Project) -> Result<()>;
-    async fn remove_project(&mut self, key: String) -> Result<u32>;
-    async fn get_project(&mut self, key: String) -> Result<Project>;
-    async fn list_project(&mut self) -> Result<Vec<Project>>;
+    async fn create_proj_table(&self) -> Result<()>;
+    async fn insert_project(&self, entity: Project) -> Result<()>;
+    async fn remove_project(&self, key: String) -> Result<u32>;
+    /// Update the description, path, colour and defaults of the project
+    async fn update_project(&self, entity: Project) -> Result<u64>;
+    async fn get_project(&self, key: String) -> Result<Project>;
+    async fn list_project(&self) -> Result<Vec<Project>>;
     async fn list_project_with_filter<T: Fn(&Project) -> bool + Send + Sync>(
-        &mut self,
+        &self,
         pred: T,
     ) -> Result<Vec<Project>>;
 }
 
 #[async_trait]
 pub trait NoteRepository: Initable {
-    async fn create_note_table(&mut self) -> Result<()>;
-    async fn insert_note(&mut self, entity: Note) -> Result<()>;
-    async fn remove_note(&mut self, key: String) -> Result<u32>;
-    async fn get_note(&mut self, key: String) -> Result<Note>;
-    async fn list_note(&mut self) -> Result<Vec<Note>>;
+    async fn create_note_table(&self) -> Result<()>;
+    async fn insert_note(&self, entity: Note) -> Result<()>;
+    async fn remove_note(&self, key: String) -> Result<u32>;
+    async fn get_note(&self, key: String) -> Result<Note>;
+    async fn list_note(&self) -> Result<Vec<Note>>;
     async fn list_note_with_filter<T: Fn(&Note) -> bool + Send + Sync>(
-        &mut self,
+        &self,
         pred: T,
     ) -> Result<Vec<Note>>;
-    async fn update_note(&mut self, key: String, text: String, project_id: String) -> Result<u64>;
+    async fn update_note(&self, key: String, text: String, project_id: String) -> Result<u64>;
+    /// Remove all the notes at once, either all of them are removed or none
+    async fn remove_notes(&self, keys: Vec<String>) -> Result<u32>;
+    /// Update content and project of all the notes at once, either all of them are updated or none
+    async fn update_notes(&self, entities: Vec<Note>) -> Result<u64>;
 }
 
-#[derive(Debug, Clone, FromRow)]
+#[async_trait]
+pub trait AttachmentRepository: Initable {
+    async fn create_attachment_table(&self) -> Result<()>;
+    async fn insert_attachment(&self, entity: Attachment) -> Result<()>;
+    async fn remove_attachment(&self, key: String) -> Result<u32>;
+    async fn list_attachment(&self) -> Result<Vec<Attachment>>;
+    async fn list_attachment_with_filter<T: Fn(&Attachment) -> bool + Send + Sync>(
+        &self,
+        pred: T,
+    ) -> Result<Vec<Attachment>>;
+}
+
+/// The [[...]] references between notes, kept up to date when a note is saved
+#[async_trait]
+pub trait LinkRepository: Initable {
+    async fn create_link_table(&self) -> Result<()>;
+    async fn list_link(&self) -> Result<Vec<Link>>;
+}
+
+/// Groups the writes of a command, from begin they only take effect on commit
+///
+/// Dropping the repository with a transaction still open rolls it back.
+#[async_trait]
+pub trait Transactional {
+    async fn begin(&self) -> Result<()>;
+    async fn commit(&self) -> Result<()>;
+    async fn rollback(&self) -> Result<()>;
+}
+
+/// Commit the transaction if the writes went through, roll it back and pass the error on otherwise
+pub(crate) async fn finish<R: Transactional, T>(repo: &R, result: Result<T>) -> Result<T> {
+    match result {
+        Ok(value) => {
+            repo.commit().await?;
+            Ok(value)
+        }
+        Err(err) => {
+            repo.rollback().await?;
+            Err(err)
+        }
+    }
+}
+
+/// Pool of a sql repository along with the transaction opened by begin, if any
+pub(crate) struct TransactionPool<DB: Database> {
+    pool: Pool<DB>,
+    tx: Mutex<Option<Transaction<'static, DB>>>,
+}
+
+impl<DB: Database> TransactionPool<DB> {
+    pub fn new(pool: Pool<DB>) -> Self {
+        Self {
+            pool,
+            tx: Mutex::new(None),
+        }
+    }
+
+    pub fn pool(&self) -> &Pool<DB> {
+        &self.pool
+    }
+
+    /// Connection for the next query, the one of the open transaction if there is one
+    pub async fn conn(&self) -> Result<Conn<'_, DB>> {
+        let tx = self.tx.lock().await;
+        if tx.is_some() {
+            return Ok(Conn::Transaction(tx));
+        }
+        drop(tx);
+        Ok(Conn::Pool(self.pool.acquire().await?))
+    }
+
+    pub async fn begin(&self) -> Result<()> {
+        let mut tx = self.tx.lock().await;
+        if tx.is_some() {
+            return Err(anyhow!("A transaction is already open"));
+        }
+        *tx = Some(self.pool.begin().await?);
+        Ok(())
+    }
+
+    pub async fn commit(&self) -> Result<()> {
+        let tx = self.tx.lock().await.take();
+        tx.ok_or(anyhow!("No transaction is open"))?
+            .commit()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn rollback(&self) -> Result<()> {
+        let tx = self.tx.lock().await.take();
+        tx.ok_or(anyhow!("No transaction is open"))?
+            .rollback()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Connection borrowed from a transaction pool
+pub(crate) enum Conn<'a, DB: Database> {
+    Transaction(MutexGuard<'a, Option<Transaction<'static, DB>>>),
+    Pool(PoolConnection<DB>),
+}
+
+impl<DB: Database> Deref for Conn<'_, DB> {
+    type Target = DB::Connection;
+
+    fn deref(&self) -> &DB::Connection {
+        match self {
+            Conn::Transaction(tx) => tx.as_ref().expect("Transaction checked when locked"),
+            Conn::Pool(conn) => conn,
+        }
+    }
+}
+
+impl<DB: Database> DerefMut for Conn<'_, DB> {
+    fn deref_mut(&mut self) -> &mut DB::Connection {
+        match self {
+            Conn::Transaction(tx) => tx.as_mut().expect("Transaction checked when locked"),
+            Conn::Pool(conn) => conn,
+        }
+    }
+}
+
+/// Turn constraint violations of the database into errors a user can act on
+///
+/// Unique violations are told apart by the constraint on postgres and by the columns at the end
+/// of the message on sqlite, the ones of any other constraint are passed on as they are.
+pub(crate) fn describe_constraint(err: sqlx::Error) -> anyhow::Error {
+    let Some(db) = err.as_database_error() else {
+        return err.into();
+    };
+    let violated = |names: &[&str]| {
+        names
+            .iter()
+            .any(|name| db.constraint() == Some(*name) || db.message().ends_with(name))
+    };
+    let project_name = violated(&["project_name_key", "project.name"]);
+    let note_number = violated(&["note_project_number", "note.project_id, note.number"]);
+    match db.kind() {
+        ErrorKind::ForeignKeyViolation => anyhow!("The project of the note does not exist"),
+        ErrorKind::UniqueViolation if project_name => {
+            anyhow!("A project with this name already exists")
+        }
+        ErrorKind::UniqueViolation if note_number => {
+            anyhow!("The number of the note is already taken in its project")
+        }
+        _ => err.into(),
+    }
+}
+
+/// Refuse a project name whose segments would leave the notes directory, or clash with the
+/// files kept next to the projects like .git or .attachments
+pub(crate) fn check_project_name(name: &str) -> Result<()> {
+    let valid = name.split('/').all(|segment| {
+        !segment.trim().is_empty() && !segment.starts_with('.') && !segment.contains('\\')
+    });
+    if !valid {
+        return Err(anyhow!(
+            "Invalid project name {name}, the parts between / cannot be empty, start with a dot or hold a \\"
+        ));
+    }
+    Ok(())
+}
+
+/// Keep the items matching the predicate, for repositories that cannot filter on their side
+pub(crate) fn apply_filter<T: Clone, K: Fn(&T) -> bool + Send + Sync>(
+    pred: K,
+    items: Vec<T>,
+) -> Vec<T> {
+    items
+        .iter()
+        .filter_map(|it| if pred(it) { Some(it.to_owned()) } else { None })
+        .collect_vec()
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct Project {
     id: String,
+    /// Full path of the project, e.g. backend/auth for auth under backend
     name: String,
     ts: chrono::NaiveDateTime,
+    #[serde(default)]
+    parent_id: Option<String>,
+    #[serde(default)]
+    description: String,
+    /// Directory or repository the project is about
+    #[serde(default)]
+    path: String,
+    /// Colour of the name in listings, e.g. red or dark_cyan
+    #[serde(default)]
+    colour: String,
+    /// Name of new notes, overriding default_name of the configuration
+    #[serde(default)]
+    default_name: String,
+    /// Number of the next note of the project, the numbers of removed notes are not given out again
+    #[serde(default)]
+    next_number: i64,
 }
 
 impl Project {
@@ -56,51 +261,190 @@ impl Project {
     pub fn ts(&self) -> chrono::NaiveDateTime {
         self.ts
     }
+    pub fn parent_id(&self) -> Option<&str> {
+        self.parent_id.as_deref()
+    }
+    /// Path of the parent project, None for a top level one
+    pub fn parent_name(&self) -> Option<&str> {
+        self.name.rsplit_once('/').map(|(parent, _)| parent)
+    }
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    pub fn colour(&self) -> &str {
+        &self.colour
+    }
+    pub fn default_name(&self) -> &str {
+        &self.default_name
+    }
+    pub fn next_number(&self) -> i64 {
+        self.next_number
+    }
+    pub fn with_parent_id(self, parent_id: Option<String>) -> Self {
+        Self { parent_id, ..self }
+    }
+    pub fn with_description(self, description: String) -> Self {
+        Self {
+            description,
+            ..self
+        }
+    }
+    pub fn with_path(self, path: String) -> Self {
+        Self { path, ..self }
+    }
+    pub fn with_colour(self, colour: String) -> Self {
+        Self { colour, ..self }
+    }
+    pub fn with_default_name(self, default_name: String) -> Self {
+        Self {
+            default_name,
+            ..self
+        }
+    }
+    pub fn with_next_number(self, next_number: i64) -> Self {
+        Self {
+            next_number,
+            ..self
+        }
+    }
     pub fn new(guid: String, name: String, ts: chrono::NaiveDateTime) -> Self {
-        Self { id: guid, name, ts }
+        Self {
+            id: guid,
+            name,
+            ts,
+            parent_id: None,
+            description: "".to_string(),
+            path: "".to_string(),
+            colour: "".to_string(),
+            default_name: "".to_string(),
+            next_number: 1,
+        }
     }
 }
 
-#[derive(Debug, Clone, FromRow)]
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct Note {
     id: String,
     project_id: String,
+    number: i64,
     name: String,
+    #[serde(default)]
+    author: String,
+    /// When the note needs to be followed up
+    #[serde(default)]
+    due: Option<chrono::NaiveDateTime>,
+    /// Printed before the other notes of its project
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    starred: bool,
+    /// One of the statuses of the configuration, empty if the note has none
+    #[serde(default)]
+    status: String,
+    /// Kept out of serialization, file based backends store it as the body
+    #[serde(skip)]
     content: String,
     ts: chrono::NaiveDateTime,
+    /// Set on every write, sync keeps the latest of two conflicting versions
+    #[serde(default)]
+    modified: chrono::NaiveDateTime,
 }
 
 impl Note {
-    pub(crate) fn get_print(&self, config: &Config, no_guid: bool) -> String {
+    pub(crate) fn get_print(
+        &self,
+        config: &Config,
+        no_guid: bool,
+        project: Option<&str>,
+        attachments: usize,
+    ) -> String {
         let mut builder = String::new();
         if no_guid {
             builder.push_str(format!("{}\n", self.guid()).as_str());
         }
         builder.push_str(
             format!(
-                "{}{}{}\n",
+                "{}#{} {}{}{}{}{}{}{}\n",
+                project.unwrap_or(""),
+                self.number(),
                 empty_or_value(self.name().to_string(), self.name().to_string()),
                 if self.name().is_empty() { "" } else { "|" },
-                self.ts()
-                    .format(if !config.include_time() {
-                        "%Y-%m-%d"
-                    } else {
-                        "%Y-%m-%d %H:%M:%S"
-                    })
-                    .to_string()
+                self.ts().format(if !config.include_time() {
+                    "%Y-%m-%d"
+                } else {
+                    "%Y-%m-%d %H:%M:%S"
+                }),
+                if self.author().is_empty() {
+                    "".to_string()
+                } else {
+                    format!(" by {}", self.author())
+                },
+                match self.due() {
+                    Some(due) => format!(" due {}", due.format("%Y-%m-%d %H:%M")),
+                    None => "".to_string(),
+                },
+                attachment_label(attachments),
+                self.flags_label()
             )
             .as_str(),
         );
         builder.push_str(
             format!(
                 "{}\n",
-                empty_or_value(self.content().to_string(), "<EMPTY>".to_string())
+                empty_or_value(self.shown_content().to_string(), "<EMPTY>".to_string())
             )
             .as_str(),
         );
         builder.push_str("=====================================");
         builder
     }
+
+    /// Suffix of the header of a note with a status or pinned or starred
+    pub(crate) fn flags_label(&self) -> String {
+        let flags = [
+            (!self.status().is_empty(), self.status()),
+            (self.pinned(), "pinned"),
+            (self.starred(), "starred"),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| flag)
+        .join(", ");
+        if flags.is_empty() {
+            flags
+        } else {
+            format!(" [{flags}]")
+        }
+    }
+
+    /// Whether the content is encrypted with a passphrase of its own
+    pub(crate) fn is_secret(&self) -> bool {
+        self.content.starts_with(SECRET)
+    }
+
+    /// Content to print, secret notes stay hidden
+    pub(crate) fn shown_content(&self) -> &str {
+        if self.is_secret() {
+            "<ENCRYPTED>"
+        } else {
+            &self.content
+        }
+    }
+
+    /// One line description of the note
+    pub(crate) fn get_summary(&self) -> String {
+        format!(
+            "#{} {}{}{} {}",
+            self.number(),
+            self.name(),
+            if self.name().is_empty() { "" } else { "|" },
+            self.ts().format("%Y-%m-%d %H:%M:%S"),
+            self.guid()
+        )
+    }
 }
 
 impl Note {
@@ -110,15 +454,68 @@ impl Note {
     pub fn project_id(&self) -> &String {
         &self.project_id
     }
+    /// Sequential number of the note inside its project, 0 until inserted
+    pub fn number(&self) -> i64 {
+        self.number
+    }
     pub fn name(&self) -> &str {
         &self.name
     }
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+    pub fn due(&self) -> Option<chrono::NaiveDateTime> {
+        self.due
+    }
+    pub fn pinned(&self) -> bool {
+        self.pinned
+    }
+    pub fn starred(&self) -> bool {
+        self.starred
+    }
+    pub fn status(&self) -> &str {
+        &self.status
+    }
     pub fn content(&self) -> &str {
         &self.content
     }
     pub fn ts(&self) -> chrono::NaiveDateTime {
         self.ts
     }
+    /// Last time the note was written, notes of an older version count from their creation
+    pub fn modified(&self) -> chrono::NaiveDateTime {
+        self.modified.max(self.ts)
+    }
+    pub fn with_content(self, content: String) -> Self {
+        Self { content, ..self }
+    }
+    pub fn with_name(self, name: String) -> Self {
+        Self { name, ..self }
+    }
+    pub fn with_project_id(self, project_id: String) -> Self {
+        Self { project_id, ..self }
+    }
+    pub fn with_number(self, number: i64) -> Self {
+        Self { number, ..self }
+    }
+    pub fn with_author(self, author: String) -> Self {
+        Self { author, ..self }
+    }
+    pub fn with_due(self, due: Option<chrono::NaiveDateTime>) -> Self {
+        Self { due, ..self }
+    }
+    pub fn with_pinned(self, pinned: bool) -> Self {
+        Self { pinned, ..self }
+    }
+    pub fn with_starred(self, starred: bool) -> Self {
+        Self { starred, ..self }
+    }
+    pub fn with_status(self, status: String) -> Self {
+        Self { status, ..self }
+    }
+    pub fn with_modified(self, modified: chrono::NaiveDateTime) -> Self {
+        Self { modified, ..self }
+    }
     pub fn new(
         guid: String,
         project_id: String,
@@ -129,9 +526,175 @@ impl Note {
         Self {
             id: guid,
             project_id,
+            number: 0,
             name,
+            author: "".to_string(),
+            due: None,
+            pinned: false,
+            starred: false,
+            status: "".to_string(),
             content,
             ts,
+            modified: ts,
+        }
+    }
+}
+
+/// Targets of the [[...]] references in the content, each one a note id or a note name
+pub(crate) fn parse_links(content: &str) -> Vec<String> {
+    let mut links = vec![];
+    let mut rest = content;
+    while let Some(start) = rest.find("[[") {
+        rest = &rest[start + 2..];
+        let Some(end) = rest.find("]]") else {
+            break;
+        };
+        let target = rest[..end].trim();
+        if !target.is_empty() && !target.contains('\n') && !links.iter().any(|it| it == target) {
+            links.push(target.to_string());
+        }
+        rest = &rest[end + 2..];
+    }
+    links
+}
+
+/// Links of every note, read from the content instead of a table
+pub(crate) fn links_of(notes: &[Note]) -> Vec<Link> {
+    notes
+        .iter()
+        .flat_map(|note| {
+            parse_links(note.content())
+                .into_iter()
+                .map(|target| Link::new(note.guid().to_owned(), target))
+        })
+        .collect_vec()
+}
+
+/// Whether the line is a Markdown checkbox, with its text if it is
+fn checkbox(line: &str) -> Option<(bool, &str)> {
+    let rest = line.trim_start();
+    let rest = ["- ", "* ", "+ "]
+        .iter()
+        .find_map(|bullet| rest.strip_prefix(bullet))?
+        .trim_start();
+    let (done, text) = if let Some(text) = rest.strip_prefix("[ ]") {
+        (false, text)
+    } else {
+        (true, rest.strip_prefix("[x]").or(rest.strip_prefix("[X]"))?)
+    };
+    if !text.is_empty() && !text.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some((done, text.trim()))
+}
+
+/// The "- [ ]" and "- [x]" items of the content as (done, text), numbered from 1 in this order
+pub(crate) fn parse_checkboxes(content: &str) -> Vec<(bool, String)> {
+    content
+        .lines()
+        .filter_map(checkbox)
+        .map(|(done, text)| (done, text.to_string()))
+        .collect_vec()
+}
+
+/// The content with its n-th checkbox ticked
+pub(crate) fn tick_checkbox(content: &str, n: usize) -> Result<String> {
+    let mut count = 0;
+    let mut ticked = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        if let Some((done, _)) = checkbox(line) {
+            count += 1;
+            if count == n {
+                if done {
+                    return Err(anyhow!("Item {n} is already done"));
+                }
+                ticked.push_str(&line.replacen("[ ]", "[x]", 1));
+                continue;
+            }
         }
+        ticked.push_str(line);
+    }
+    if n == 0 || count < n {
+        return Err(anyhow!("The note has no item {n}"));
+    }
+    Ok(ticked)
+}
+
+/// Suffix of the header of a note with attachments
+pub(crate) fn attachment_label(count: usize) -> String {
+    match count {
+        0 => "".to_string(),
+        1 => " [1 attachment]".to_string(),
+        count => format!(" [{count} attachments]"),
+    }
+}
+
+/// File attached to a note, its content is stored by hash in the attachments directory
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Attachment {
+    id: String,
+    note_id: String,
+    name: String,
+    hash: String,
+    size: i64,
+    ts: chrono::NaiveDateTime,
+}
+
+impl Attachment {
+    pub fn guid(&self) -> &String {
+        &self.id
+    }
+    pub fn note_id(&self) -> &String {
+        &self.note_id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Sha256 of the content, the name of the stored file
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+    pub fn ts(&self) -> chrono::NaiveDateTime {
+        self.ts
+    }
+    pub fn new(
+        guid: String,
+        note_id: String,
+        name: String,
+        hash: String,
+        size: i64,
+        ts: chrono::NaiveDateTime,
+    ) -> Self {
+        Self {
+            id: guid,
+            note_id,
+            name,
+            hash,
+            size,
+            ts,
+        }
+    }
+}
+
+/// Reference from a note to another one, the target is resolved when the links are read
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Link {
+    note_id: String,
+    target: String,
+}
+
+impl Link {
+    pub fn note_id(&self) -> &String {
+        &self.note_id
+    }
+    /// Id, id prefix or name of the linked note as written in the content
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+    pub fn new(note_id: String, target: String) -> Self {
+        Self { note_id, target }
     }
 }
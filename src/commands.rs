@@ -1,10 +1,11 @@
-use std::fs;
-use std::io::stdout;
-use std::path::PathBuf;
+use std::io::{stdin, stdout, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::{env, fs};
 
 use anyhow::anyhow;
 use anyhow::Result;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use crossterm::event::{KeyCode, KeyEventKind, KeyModifiers};
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
@@ -18,11 +19,12 @@ use ratatui::Terminal;
 use uuid::Uuid;
 
 use crate::backend::{Note, Project};
+use crate::config;
 
-pub(crate) fn check_guid_prefix_match(notes: &Vec<Note>) -> anyhow::Result<()> {
+pub(crate) fn check_guid_prefix_match(notes: &[Note]) -> anyhow::Result<()> {
     match notes.len() {
-        x if x == 0 => Err(anyhow!("This gid does not exist in this database")),
-        x if x == 1 => Ok(()),
+        0 => Err(anyhow!("This gid does not exist in this database")),
+        1 => Ok(()),
         _ => Err(anyhow!(
             "This gid prefix holds multiple results in this database"
         )),
@@ -30,8 +32,212 @@ pub(crate) fn check_guid_prefix_match(notes: &Vec<Note>) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub(crate) fn match_guid_prefix(name: &String) -> impl Fn(&Note) -> bool + '_ {
-    move |it: &Note| it.guid().starts_with(name)
+/// Match a note either by guid prefix or by its number in the given project ("#14")
+pub(crate) fn match_note_ref<'a>(
+    reference: &'a str,
+    proj_id: &'a str,
+) -> impl Fn(&Note) -> bool + 'a {
+    let number = reference
+        .strip_prefix('#')
+        .map(|it| it.parse::<i64>().unwrap_or(-1));
+    move |it: &Note| match number {
+        Some(number) => it.project_id() == proj_id && it.number() == number,
+        None => it.guid().starts_with(reference),
+    }
+}
+
+/// Match notes by day range, name, contained text and #tag, ignoring the filters that are None
+pub(crate) fn match_query<'a>(
+    before: Option<NaiveDate>,
+    after: Option<NaiveDate>,
+    name: Option<&'a str>,
+    contains: Option<&'a str>,
+    tag: Option<&'a str>,
+) -> impl Fn(&Note) -> bool + 'a {
+    move |it: &Note| {
+        before.is_none_or(|day| it.ts().date() < day)
+            && after.is_none_or(|day| it.ts().date() >= day)
+            && name.is_none_or(|name| it.name() == name)
+            && contains.is_none_or(|text| it.content().contains(text))
+            && tag.is_none_or(|tag| {
+                it.content()
+                    .split_whitespace()
+                    .any(|word| word.strip_prefix('#') == Some(tag))
+            })
+    }
+}
+
+/// Parse an optional day given as YYYY-MM-DD
+pub(crate) fn parse_day(day: &Option<String>) -> Result<Option<NaiveDate>> {
+    match day {
+        None => Ok(None),
+        Some(day) => Ok(Some(NaiveDate::parse_from_str(day, "%Y-%m-%d")?)),
+    }
+}
+
+/// Parse a due date given as YYYY-MM-DD, YYYY-MM-DD HH:MM or +<n>h, +<n>d, +<n>w from now
+///
+/// A day without a time is due at its end.
+pub(crate) fn parse_due(due: &str) -> Result<NaiveDateTime> {
+    if let Some(offset) = due.strip_prefix('+') {
+        // The unit is the last char, which may take more than one byte
+        let split = offset.char_indices().last().map_or(0, |(index, _)| index);
+        let (count, unit) = offset.split_at(split);
+        let count: i64 = count
+            .parse()
+            .map_err(|_| anyhow!("Invalid due date {due}"))?;
+        let offset = match unit {
+            "h" => Duration::try_hours(count),
+            "d" => Duration::try_days(count),
+            "w" => Duration::try_weeks(count),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid due date {due}, expected +<n>h, +<n>d or +<n>w"
+                ))
+            }
+        };
+        return offset
+            .and_then(|offset| Utc::now().naive_utc().checked_add_signed(offset))
+            .ok_or(anyhow!("Invalid due date {due}, too far ahead"));
+    }
+    if let Ok(day) = NaiveDate::parse_from_str(due, "%Y-%m-%d") {
+        return Ok(day.and_time(NaiveTime::from_hms_opt(23, 59, 59).unwrap()));
+    }
+    NaiveDateTime::parse_from_str(due, "%Y-%m-%d %H:%M")
+        .map_err(|_| anyhow!("Invalid due date {due}, expected YYYY-MM-DD or YYYY-MM-DD HH:MM"))
+}
+
+/// Ask a yes/no question on the terminal, anything but y/yes is a no
+pub(crate) fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Ask a question on the terminal, an empty answer picks the default
+pub(crate) fn ask(question: &str, default: &str) -> Result<String> {
+    if default.is_empty() {
+        print!("{question} ");
+    } else {
+        print!("{question} [{default}] ");
+    }
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    match answer.trim() {
+        "" => Ok(default.to_string()),
+        answer => Ok(answer.to_string()),
+    }
+}
+
+/// Ask a yes/no question on the terminal until the answer is one of them
+fn ask_yes_no(question: &str) -> Result<bool> {
+    loop {
+        match ask(&format!("{question} [y/N]"), "")?
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "n" | "no" => return Ok(false),
+            "y" | "yes" => return Ok(true),
+            _ => println!("Please answer y or n"),
+        }
+    }
+}
+
+/// Ask for the main settings on the first run and write them to the configuration file
+pub(crate) fn setup_wizard(path: &Path) -> Result<()> {
+    println!("Welcome to devnotes, press enter to keep the default of a setting.");
+    println!("They can all be changed later with the config command.");
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or("internal".to_string());
+    let editor = ask("Editor command, internal for the built-in one", &editor)?;
+    let settings = [
+        (
+            "edit_app",
+            if editor == "internal" {
+                "".to_string()
+            } else {
+                editor
+            },
+        ),
+        ("default_name", ask("Default name of new notes", "")?),
+        (
+            "include_time",
+            ask_yes_no("Show the time of the notes along with the day?")?.to_string(),
+        ),
+        (
+            "group_by_date",
+            ask_yes_no("Group the listed notes by date?")?.to_string(),
+        ),
+        (
+            "data_dir",
+            ask("Directory of the notes, relative to the executable", ".")?,
+        ),
+    ];
+    for (key, value) in settings {
+        config::set_value(path, key, &value)?;
+    }
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+/// Match notes whose author contains the text, ignoring case
+pub(crate) fn match_author(author: &Option<String>) -> impl Fn(&Note) -> bool + '_ {
+    move |it: &Note| {
+        author
+            .as_ref()
+            .is_none_or(|author| it.author().to_lowercase().contains(&author.to_lowercase()))
+    }
+}
+
+/// Match the notes with the status, "none" matching the ones without any
+pub(crate) fn match_status(status: &Option<String>) -> impl Fn(&Note) -> bool + '_ {
+    move |it: &Note| match status.as_deref() {
+        None => true,
+        Some("none") => it.status().is_empty(),
+        Some(status) => it.status() == status,
+    }
+}
+
+/// Shortest id prefix a [[...]] link is resolved by, shorter ones are too likely to be a word
+const MIN_LINK_PREFIX: usize = 4;
+
+/// Notes a [[...]] link points at, the notes with that name or else the notes whose id starts with it
+pub(crate) fn resolve_link<'a>(notes: &'a [Note], target: &str) -> Vec<&'a Note> {
+    let named = notes.iter().filter(|it| it.name() == target).collect_vec();
+    if !named.is_empty() {
+        return named;
+    }
+    let is_prefix = target.len() >= MIN_LINK_PREFIX
+        && target.chars().all(|it| it.is_ascii_hexdigit() || it == '-');
+    if !is_prefix {
+        return vec![];
+    }
+    notes
+        .iter()
+        .filter(|it| it.guid().starts_with(target))
+        .collect_vec()
+}
+
+/// Ids of the projects along with the ids of all the projects nested in them
+pub(crate) fn with_descendants<'a>(
+    projects: &[Project],
+    ids: impl IntoIterator<Item = &'a String>,
+) -> Vec<String> {
+    let mut ids = ids.into_iter().cloned().collect_vec();
+    let mut pending = ids.clone();
+    while let Some(id) = pending.pop() {
+        for child in projects.iter().filter(|it| it.parent_id() == Some(&id)) {
+            if !ids.contains(child.guid()) {
+                ids.push(child.guid().to_owned());
+                pending.push(child.guid().to_owned());
+            }
+        }
+    }
+    ids
 }
 
 pub(crate) fn match_name(name: &String) -> impl Fn(&Project) -> bool + '_ {
@@ -78,10 +284,7 @@ impl Editor for InternalEditor {
                 if let Some(n) = &name {
                     frame.render_widget(format!("{}|", n), title[0]);
                 }
-                frame.render_widget(
-                    format!("{}", date.format("%Y-%m-%d %H:%M:%S").to_string()),
-                    title[1],
-                );
+                frame.render_widget(date.format("%Y-%m-%d %H:%M:%S").to_string(), title[1]);
                 frame.render_widget(textarea.widget(), layout[1]);
             })?;
 
@@ -94,7 +297,7 @@ impl Editor for InternalEditor {
                     {
                         break 'main_loop;
                     }
-                    (&mut textarea).input(key);
+                    textarea.input(key);
                 }
             }
         }
@@ -140,13 +343,13 @@ impl Editor for ExternalEditor {
             } else {
                 format!("{}|", unwrapped_name)
             },
-            date.format("%Y-%m-%d %H:%M:%S").to_string(),
+            date.format("%Y-%m-%d %H:%M:%S"),
         );
         builder.push_str("\nDO NOT EDIT ABOVE THE LINE, CAUSE IT WILL NOT BE RECORDED(===)");
         builder.push_str("\n====================\n");
         builder.push_str(
             if let Some(te) = text {
-                format!("{}", te)
+                te
             } else {
                 "".to_string()
             }
@@ -173,3 +376,42 @@ pub(crate) trait Editor {
         text: Option<String>,
     ) -> Result<String>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(guid: &str, name: &str) -> Note {
+        Note::new(
+            guid.to_string(),
+            "project".to_string(),
+            name.to_string(),
+            "".to_string(),
+            Utc::now().naive_utc(),
+        )
+    }
+
+    fn resolved(notes: &[Note], target: &str) -> Vec<String> {
+        resolve_link(notes, target)
+            .iter()
+            .map(|it| it.name().to_string())
+            .collect_vec()
+    }
+
+    #[test]
+    fn links_resolve_by_name_before_id() {
+        let notes = [
+            note("deadbeef-0000", "design"),
+            note("dead0000-0000", "deadbeef"),
+            note("a1b2c3d4-0000", "api"),
+        ];
+        assert_eq!(resolved(&notes, "deadbeef"), ["deadbeef"]);
+        assert_eq!(resolved(&notes, "a1b2"), ["api"]);
+        assert_eq!(resolved(&notes, "dead"), ["design", "deadbeef"]);
+        // Words and short prefixes are not ids
+        assert!(resolved(&notes, "a").is_empty());
+        assert!(resolved(&notes, "a1b").is_empty());
+        assert!(resolved(&notes, "a1b2 notes").is_empty());
+        assert!(resolved(&notes, "").is_empty());
+    }
+}
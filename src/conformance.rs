@@ -0,0 +1,375 @@
+//! Behaviour every backend has to share, run against each of them
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use itertools::Itertools;
+use uuid::Uuid;
+
+use crate::backend::{
+    Attachment, AttachmentRepository, LinkRepository, Note, NoteRepository, Project,
+    ProjectRepository, Transactional,
+};
+use crate::files::FilesRepository;
+use crate::postgres::PostgresRepository;
+use crate::sqlite::SqliteRepository;
+
+/// Directory removed with everything in it when dropped
+pub(crate) struct TempDir(pub(crate) PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Result<TempDir> {
+        let dir = env::temp_dir().join(format!("devnotes-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        Ok(TempDir(dir))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Seconds only, the precision every backend keeps
+fn ts(day: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2024, 5, day)
+        .and_then(|it| it.and_hms_opt(12, 0, 0))
+        .unwrap()
+}
+
+/// Project with a unique name, so that the suite can run on a database in use
+async fn project<R: ProjectRepository>(repo: &R) -> Result<Project> {
+    let project = Project::new(
+        Uuid::new_v4().to_string(),
+        format!("conformance-{}", Uuid::new_v4()),
+        ts(1),
+    );
+    repo.insert_project(project.clone()).await?;
+    Ok(project)
+}
+
+async fn note<R: NoteRepository>(repo: &R, project: &Project, content: &str) -> Result<Note> {
+    let note = Note::new(
+        Uuid::new_v4().to_string(),
+        project.guid().to_owned(),
+        "note".to_string(),
+        content.to_string(),
+        ts(2),
+    );
+    repo.insert_note(note.clone()).await?;
+    repo.get_note(note.guid().to_owned()).await
+}
+
+async fn numbering<R: NoteRepository + ProjectRepository>(repo: &R) -> Result<()> {
+    let project = project(repo).await?;
+    let first = note(repo, &project, "first").await?;
+    let second = note(repo, &project, "second").await?;
+    let third = note(repo, &project, "third").await?;
+    assert_eq!([first.number(), second.number(), third.number()], [1, 2, 3]);
+    // Numbers of removed notes are not given out again
+    repo.remove_note(third.guid().to_owned()).await?;
+    assert_eq!(note(repo, &project, "fourth").await?.number(), 4);
+    repo.remove_notes(vec![second.guid().to_owned()]).await?;
+    assert_eq!(note(repo, &project, "fifth").await?.number(), 5);
+    Ok(())
+}
+
+async fn upsert<R: NoteRepository + ProjectRepository>(repo: &R) -> Result<()> {
+    let project = project(repo).await?;
+    let note = note(repo, &project, "draft").await?;
+    let due = Some(ts(20));
+    repo.insert_note(
+        note.clone()
+            .with_name("renamed".to_string())
+            .with_content("final".to_string())
+            .with_due(due)
+            .with_pinned(true)
+            .with_starred(true)
+            .with_status("done".to_string()),
+    )
+    .await?;
+    let notes = repo
+        .list_note_with_filter(|it: &Note| it.project_id() == project.guid())
+        .await?;
+    assert_eq!(notes.len(), 1);
+    let stored = &notes[0];
+    assert_eq!(stored.number(), note.number());
+    assert_eq!(stored.name(), "renamed");
+    assert_eq!(stored.content(), "final");
+    assert_eq!(stored.due(), due);
+    assert!(stored.pinned() && stored.starred());
+    assert_eq!(stored.status(), "done");
+
+    // An upsert into another project moves the note there under the next number of that project
+    let target = self::project(repo).await?;
+    self::note(repo, &target, "already there").await?;
+    repo.insert_note(
+        stored
+            .clone()
+            .with_project_id(target.guid().to_owned())
+            .with_content("moved".to_string()),
+    )
+    .await?;
+    let moved = repo.get_note(note.guid().to_owned()).await?;
+    assert_eq!(moved.project_id(), target.guid());
+    assert_eq!(moved.number(), 2);
+    assert_eq!(moved.content(), "moved");
+    assert_eq!(self::note(repo, &target, "next").await?.number(), 3);
+    assert_eq!(self::note(repo, &project, "after").await?.number(), 2);
+    Ok(())
+}
+
+async fn update_and_move<R: NoteRepository + ProjectRepository>(repo: &R) -> Result<()> {
+    let source = project(repo).await?;
+    let target = project(repo).await?;
+    let moved = note(repo, &source, "moved").await?;
+    let kept = note(repo, &source, "kept").await?;
+    note(repo, &target, "already there").await?;
+
+    let count = repo
+        .update_note(
+            kept.guid().to_owned(),
+            "edited".to_string(),
+            source.guid().to_owned(),
+        )
+        .await?;
+    assert_eq!(count, 1);
+    let stored = repo.get_note(kept.guid().to_owned()).await?;
+    assert_eq!(stored.content(), "edited");
+    assert_eq!(stored.number(), kept.number());
+
+    // A moved note takes the next number of its new project
+    let count = repo
+        .update_notes(vec![moved
+            .clone()
+            .with_project_id(target.guid().to_owned())
+            .with_status("open".to_string())
+            .with_starred(true)])
+        .await?;
+    assert_eq!(count, 1);
+    let stored = repo.get_note(moved.guid().to_owned()).await?;
+    assert_eq!(stored.project_id(), target.guid());
+    assert_eq!(stored.number(), 2);
+    assert_eq!(stored.status(), "open");
+    assert!(stored.starred());
+    assert_eq!(
+        repo.update_note(
+            Uuid::new_v4().to_string(),
+            "nothing".to_string(),
+            source.guid().to_owned()
+        )
+        .await?,
+        0
+    );
+    Ok(())
+}
+
+async fn atomic_batches<R: NoteRepository + ProjectRepository>(repo: &R) -> Result<()> {
+    let project = project(repo).await?;
+    let first = note(repo, &project, "first").await?;
+    let second = note(repo, &project, "second").await?;
+    let missing = Uuid::new_v4().to_string();
+
+    assert!(repo
+        .remove_notes(vec![first.guid().to_owned(), missing.clone()])
+        .await
+        .is_err());
+    assert!(repo.get_note(first.guid().to_owned()).await.is_ok());
+
+    let ghost = Note::new(
+        missing,
+        project.guid().to_owned(),
+        "ghost".to_string(),
+        "".to_string(),
+        ts(2),
+    );
+    assert!(repo
+        .update_notes(vec![
+            second.clone().with_content("changed".to_string()),
+            ghost
+        ])
+        .await
+        .is_err());
+    assert_eq!(
+        repo.get_note(second.guid().to_owned()).await?.content(),
+        "second"
+    );
+
+    let count = repo
+        .remove_notes(vec![first.guid().to_owned(), second.guid().to_owned()])
+        .await?;
+    assert_eq!(count, 2);
+    Ok(())
+}
+
+async fn cascade<R>(repo: &R) -> Result<()>
+where
+    R: NoteRepository + ProjectRepository + AttachmentRepository + LinkRepository,
+{
+    let project = project(repo).await?;
+    let note = note(repo, &project, "see [[elsewhere]]").await?;
+    repo.insert_attachment(attachment(&note)).await?;
+
+    assert_eq!(repo.remove_project(project.guid().to_owned()).await?, 1);
+    assert!(repo.get_project(project.guid().to_owned()).await.is_err());
+    assert!(repo.get_note(note.guid().to_owned()).await.is_err());
+    assert!(!repo
+        .list_attachment()
+        .await?
+        .iter()
+        .any(|it| it.note_id() == note.guid()));
+    assert!(!repo
+        .list_link()
+        .await?
+        .iter()
+        .any(|it| it.note_id() == note.guid()));
+    assert_eq!(repo.remove_project(project.guid().to_owned()).await?, 0);
+    Ok(())
+}
+
+fn attachment(note: &Note) -> Attachment {
+    Attachment::new(
+        Uuid::new_v4().to_string(),
+        note.guid().to_owned(),
+        "log.txt".to_string(),
+        "0".repeat(64),
+        3,
+        ts(3),
+    )
+}
+
+async fn attachments<R>(repo: &R) -> Result<()>
+where
+    R: NoteRepository + ProjectRepository + AttachmentRepository,
+{
+    let project = project(repo).await?;
+    let note = note(repo, &project, "").await?;
+    let attachment = attachment(&note);
+    repo.insert_attachment(attachment.clone()).await?;
+    let stored = repo
+        .list_attachment_with_filter(|it: &Attachment| it.note_id() == note.guid())
+        .await?;
+    assert_eq!(
+        stored.iter().map(|it| it.guid()).collect_vec(),
+        [attachment.guid()]
+    );
+    assert_eq!(stored[0].size(), 3);
+
+    let orphan = Note::new(
+        Uuid::new_v4().to_string(),
+        project.guid().to_owned(),
+        "".to_string(),
+        "".to_string(),
+        ts(2),
+    );
+    assert!(repo
+        .insert_attachment(self::attachment(&orphan))
+        .await
+        .is_err());
+
+    assert_eq!(
+        repo.remove_attachment(attachment.guid().to_owned()).await?,
+        1
+    );
+    repo.insert_attachment(self::attachment(&note)).await?;
+    repo.remove_note(note.guid().to_owned()).await?;
+    assert!(!repo
+        .list_attachment()
+        .await?
+        .iter()
+        .any(|it| it.note_id() == note.guid()));
+    Ok(())
+}
+
+async fn links<R>(repo: &R) -> Result<()>
+where
+    R: NoteRepository + ProjectRepository + LinkRepository,
+{
+    let project = project(repo).await?;
+    let note = note(repo, &project, "see [[one]] and [[two]] and [[one]]").await?;
+    let targets = |links: Vec<crate::backend::Link>| {
+        links
+            .into_iter()
+            .filter(|it| it.note_id() == note.guid())
+            .map(|it| it.target().to_string())
+            .sorted()
+            .collect_vec()
+    };
+    assert_eq!(targets(repo.list_link().await?), ["one", "two"]);
+
+    repo.update_note(
+        note.guid().to_owned(),
+        "now [[three]]".to_string(),
+        project.guid().to_owned(),
+    )
+    .await?;
+    assert_eq!(targets(repo.list_link().await?), ["three"]);
+    Ok(())
+}
+
+async fn transactions<R: ProjectRepository + Transactional>(repo: &R) -> Result<()> {
+    repo.begin().await?;
+    let parent = project(repo).await?;
+    let child = Project::new(
+        Uuid::new_v4().to_string(),
+        format!("{}/child", parent.name()),
+        ts(1),
+    )
+    .with_parent_id(Some(parent.guid().to_owned()));
+    repo.insert_project(child.clone()).await?;
+    repo.rollback().await?;
+    assert!(repo.get_project(parent.guid().to_owned()).await.is_err());
+    assert!(repo.get_project(child.guid().to_owned()).await.is_err());
+
+    repo.begin().await?;
+    let kept = project(repo).await?;
+    repo.commit().await?;
+    assert!(repo.get_project(kept.guid().to_owned()).await.is_ok());
+    assert!(repo.rollback().await.is_err());
+    Ok(())
+}
+
+/// Every check of the suite, each one on projects of its own
+pub(crate) async fn run<R>(repo: &R) -> Result<()>
+where
+    R: NoteRepository + ProjectRepository + AttachmentRepository + LinkRepository + Transactional,
+{
+    numbering(repo).await?;
+    upsert(repo).await?;
+    update_and_move(repo).await?;
+    atomic_batches(repo).await?;
+    cascade(repo).await?;
+    attachments(repo).await?;
+    links(repo).await?;
+    transactions(repo).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite() -> Result<()> {
+    let dir = TempDir::new()?;
+    let repo = SqliteRepository::open(&dir.0.join("note.db")).await?;
+    run(&repo).await
+}
+
+#[tokio::test]
+async fn files() -> Result<()> {
+    let dir = TempDir::new()?;
+    let repo = FilesRepository::open(dir.0.join("notes")).await?;
+    run(&repo).await
+}
+
+/// Needs DEVNOTES_TEST_PG_URL pointing at a database, e.g. postgres://postgres@localhost/devnotes,
+/// run it with cargo test -- --ignored
+#[tokio::test]
+#[ignore = "needs a Postgres server, set DEVNOTES_TEST_PG_URL and pass --ignored"]
+async fn postgres() -> Result<()> {
+    let url =
+        env::var("DEVNOTES_TEST_PG_URL").map_err(|_| anyhow!("DEVNOTES_TEST_PG_URL is not set"))?;
+    let repo = PostgresRepository::connect(&url).await?;
+    run(&repo).await
+}
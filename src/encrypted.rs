@@ -0,0 +1,368 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{
+    apply_filter, links_of, Attachment, AttachmentRepository, Initable, Link, LinkRepository, Note,
+    NoteRepository, Project, ProjectRepository, Transactional,
+};
+
+/// Prefix of encrypted content, content without it is plain text written before encryption was enabled
+const ENCRYPTED: &str = "devnotes-encrypted:";
+/// Known text stored encrypted to tell a wrong passphrase when unlocking
+const CHECK: &str = "devnotes";
+const PASSPHRASE_VAR: &str = "DEVNOTES_PASSPHRASE";
+/// Prefix of the content of a secret note, encrypted on its own with a passphrase and a salt of its own
+pub(crate) const SECRET: &str = "devnotes-secret:";
+const SECRET_PASSPHRASE_VAR: &str = "DEVNOTES_SECRET_PASSPHRASE";
+/// File of the data directory holding the key check, backed up along with the database
+pub(crate) const KEY_CHECK_FILE: &str = "encryption.toml";
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+/// Salt of the key and the encrypted check text, kept in the data directory
+#[derive(Serialize, Deserialize)]
+struct KeyCheck {
+    salt: String,
+    check: String,
+}
+
+/// Encrypts the content of the notes of the wrapped repository with a key derived from a passphrase
+pub struct EncryptedRepository<R> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    key_check: PathBuf,
+}
+
+impl<R: NoteRepository + ProjectRepository + Transactional + Send + Sync> EncryptedRepository<R> {
+    /// Unlock the notebook, the first time a new passphrase is set and the existing notes are encrypted
+    ///
+    /// The passphrase comes from $DEVNOTES_PASSPHRASE, else the key file, else a prompt.
+    pub(crate) async fn unlock(
+        inner: R,
+        key_check: PathBuf,
+        key_file: Option<PathBuf>,
+    ) -> Result<Self> {
+        let cipher = if key_check.exists() {
+            let check: KeyCheck = toml::from_str(&fs::read_to_string(&key_check)?)?;
+            let passphrase = passphrase(key_file.as_deref(), false)?;
+            let cipher = derive_cipher(&passphrase, &STANDARD.decode(&check.salt)?)?;
+            if decrypt(&cipher, &check.check).ok().as_deref() != Some(CHECK) {
+                return Err(anyhow!("Wrong passphrase"));
+            }
+            cipher
+        } else {
+            let passphrase = passphrase(key_file.as_deref(), true)?;
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let cipher = derive_cipher(&passphrase, &salt)?;
+            let check = KeyCheck {
+                salt: STANDARD.encode(salt),
+                check: encrypt(&cipher, CHECK)?,
+            };
+            fs::write(&key_check, toml::to_string(&check)?)?;
+            cipher
+        };
+        let repo = Self {
+            inner,
+            cipher,
+            key_check,
+        };
+        // Also picks up notes left in plain text by an interrupted run
+        let plain = repo
+            .inner
+            .list_note_with_filter(|it: &Note| !it.content().starts_with(ENCRYPTED))
+            .await?;
+        if !plain.is_empty() {
+            let count = plain.len();
+            repo.inner.update_notes(repo.seal_all(plain)?).await?;
+            println!("Encrypted {count} notes");
+        }
+        Ok(repo)
+    }
+
+    /// Write every note back in plain text and forget the key, for when encryption is turned off
+    pub(crate) async fn into_plain(self) -> Result<R> {
+        let notes = self.list_note().await?;
+        let count = notes.len();
+        self.inner.update_notes(notes).await?;
+        fs::remove_file(&self.key_check)?;
+        println!("Decrypted {count} notes");
+        Ok(self.inner)
+    }
+
+    fn seal(&self, note: Note) -> Result<Note> {
+        let content = encrypt(&self.cipher, note.content())?;
+        Ok(note.with_content(content))
+    }
+
+    fn seal_all(&self, notes: Vec<Note>) -> Result<Vec<Note>> {
+        notes.into_iter().map(|it| self.seal(it)).collect()
+    }
+
+    fn open(&self, note: Note) -> Result<Note> {
+        let content = decrypt(&self.cipher, note.content())
+            .map_err(|err| anyhow!("Could not decrypt note {}: {err}", note.guid()))?;
+        Ok(note.with_content(content))
+    }
+}
+
+/// Where the passphrase comes from, asked twice when it is a new one
+fn passphrase(key_file: Option<&Path>, new: bool) -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    if let Some(key_file) = key_file {
+        return Ok(fs::read_to_string(key_file)?.trim_end().to_string());
+    }
+    prompt_passphrase("Passphrase", new)
+}
+
+/// Passphrase of secret notes, from $DEVNOTES_SECRET_PASSPHRASE or else a prompt
+pub(crate) fn secret_passphrase(new: bool) -> Result<String> {
+    match env::var(SECRET_PASSPHRASE_VAR) {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => prompt_passphrase("Secret passphrase", new),
+    }
+}
+
+fn prompt_passphrase(label: &str, new: bool) -> Result<String> {
+    let passphrase = rpassword::prompt_password(format!("{label}: "))?;
+    if passphrase.is_empty() {
+        return Err(anyhow!("The passphrase cannot be empty"));
+    }
+    if new
+        && rpassword::prompt_password(format!("Repeat {}: ", label.to_lowercase()))? != passphrase
+    {
+        return Err(anyhow!("The passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("Could not derive the key: {err}"))?;
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// The text encrypted with a random nonce, nonce first
+fn seal_bytes(cipher: &XChaCha20Poly1305, text: &str) -> Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = cipher
+        .encrypt(&nonce, text.as_bytes())
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    Ok([nonce.as_slice(), &sealed].concat())
+}
+
+fn open_bytes(cipher: &XChaCha20Poly1305, bytes: &[u8]) -> Result<String> {
+    if bytes.len() < NONCE_LEN {
+        return Err(anyhow!("Truncated content"));
+    }
+    let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+    let plain = cipher
+        .decrypt(XNonce::from_slice(nonce), sealed)
+        .map_err(|_| anyhow!("Wrong key or corrupted content"))?;
+    Ok(String::from_utf8(plain)?)
+}
+
+fn encrypt(cipher: &XChaCha20Poly1305, text: &str) -> Result<String> {
+    Ok(format!(
+        "{ENCRYPTED}{}",
+        STANDARD.encode(seal_bytes(cipher, text)?)
+    ))
+}
+
+/// Decrypt content written by encrypt, plain text is returned as it is
+fn decrypt(cipher: &XChaCha20Poly1305, text: &str) -> Result<String> {
+    match text.strip_prefix(ENCRYPTED) {
+        Some(encoded) => open_bytes(cipher, &STANDARD.decode(encoded)?),
+        None => Ok(text.to_string()),
+    }
+}
+
+/// Encrypt the content of a secret note, the salt goes in front of the nonce
+pub(crate) fn seal_secret(text: &str, passphrase: &str) -> Result<String> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let sealed = seal_bytes(&derive_cipher(passphrase, &salt)?, text)?;
+    Ok(format!(
+        "{SECRET}{}",
+        STANDARD.encode([salt.as_slice(), &sealed].concat())
+    ))
+}
+
+/// Decrypt the content of a secret note written by seal_secret
+pub(crate) fn open_secret(text: &str, passphrase: &str) -> Result<String> {
+    let bytes = STANDARD.decode(
+        text.strip_prefix(SECRET)
+            .ok_or(anyhow!("The note is not a secret note"))?,
+    )?;
+    if bytes.len() < SALT_LEN {
+        return Err(anyhow!("Truncated content"));
+    }
+    let (salt, sealed) = bytes.split_at(SALT_LEN);
+    open_bytes(&derive_cipher(passphrase, salt)?, sealed).map_err(|_| anyhow!("Wrong passphrase"))
+}
+
+#[async_trait]
+impl<R: Initable + Send + Sync> Initable for EncryptedRepository<R> {
+    fn initialized(&self) -> bool {
+        self.inner.initialized()
+    }
+
+    async fn init(&self) -> Result<()> {
+        self.inner.init().await
+    }
+}
+
+#[async_trait]
+impl<R: Transactional + Send + Sync> Transactional for EncryptedRepository<R> {
+    async fn begin(&self) -> Result<()> {
+        self.inner.begin().await
+    }
+
+    async fn commit(&self) -> Result<()> {
+        self.inner.commit().await
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        self.inner.rollback().await
+    }
+}
+
+#[async_trait]
+impl<R: NoteRepository + ProjectRepository + Transactional + Send + Sync> NoteRepository
+    for EncryptedRepository<R>
+{
+    async fn create_note_table(&self) -> Result<()> {
+        self.inner.create_note_table().await
+    }
+
+    async fn insert_note(&self, entity: Note) -> Result<()> {
+        self.inner.insert_note(self.seal(entity)?).await
+    }
+
+    async fn remove_note(&self, key: String) -> Result<u32> {
+        self.inner.remove_note(key).await
+    }
+
+    async fn get_note(&self, key: String) -> Result<Note> {
+        self.open(self.inner.get_note(key).await?)
+    }
+
+    async fn list_note(&self) -> Result<Vec<Note>> {
+        self.inner
+            .list_note()
+            .await?
+            .into_iter()
+            .map(|it| self.open(it))
+            .collect()
+    }
+
+    async fn list_note_with_filter<T: Fn(&Note) -> bool + Send + Sync>(
+        &self,
+        pred: T,
+    ) -> Result<Vec<Note>> {
+        // The predicate may look at the content, so it runs on the decrypted notes
+        Ok(apply_filter(pred, self.list_note().await?))
+    }
+
+    async fn update_note(&self, key: String, text: String, project_id: String) -> Result<u64> {
+        let text = encrypt(&self.cipher, &text)?;
+        self.inner.update_note(key, text, project_id).await
+    }
+
+    async fn remove_notes(&self, keys: Vec<String>) -> Result<u32> {
+        self.inner.remove_notes(keys).await
+    }
+
+    async fn update_notes(&self, entities: Vec<Note>) -> Result<u64> {
+        self.inner.update_notes(self.seal_all(entities)?).await
+    }
+}
+
+#[async_trait]
+impl<R: ProjectRepository + Send + Sync> ProjectRepository for EncryptedRepository<R> {
+    async fn create_proj_table(&self) -> Result<()> {
+        self.inner.create_proj_table().await
+    }
+
+    async fn insert_project(&self, entity: Project) -> Result<()> {
+        self.inner.insert_project(entity).await
+    }
+
+    async fn remove_project(&self, key: String) -> Result<u32> {
+        self.inner.remove_project(key).await
+    }
+
+    async fn update_project(&self, entity: Project) -> Result<u64> {
+        self.inner.update_project(entity).await
+    }
+
+    async fn get_project(&self, key: String) -> Result<Project> {
+        self.inner.get_project(key).await
+    }
+
+    async fn list_project(&self) -> Result<Vec<Project>> {
+        self.inner.list_project().await
+    }
+
+    async fn list_project_with_filter<T: Fn(&Project) -> bool + Send + Sync>(
+        &self,
+        pred: T,
+    ) -> Result<Vec<Project>> {
+        self.inner.list_project_with_filter(pred).await
+    }
+}
+
+#[async_trait]
+impl<R: AttachmentRepository + Send + Sync> AttachmentRepository for EncryptedRepository<R> {
+    async fn create_attachment_table(&self) -> Result<()> {
+        self.inner.create_attachment_table().await
+    }
+
+    async fn insert_attachment(&self, entity: Attachment) -> Result<()> {
+        self.inner.insert_attachment(entity).await
+    }
+
+    async fn remove_attachment(&self, key: String) -> Result<u32> {
+        self.inner.remove_attachment(key).await
+    }
+
+    async fn list_attachment(&self) -> Result<Vec<Attachment>> {
+        self.inner.list_attachment().await
+    }
+
+    async fn list_attachment_with_filter<T: Fn(&Attachment) -> bool + Send + Sync>(
+        &self,
+        pred: T,
+    ) -> Result<Vec<Attachment>> {
+        self.inner.list_attachment_with_filter(pred).await
+    }
+}
+
+/// The backend only sees encrypted content, so the links are read from the decrypted notes
+#[async_trait]
+impl<R: NoteRepository + ProjectRepository + Transactional + Send + Sync> LinkRepository
+    for EncryptedRepository<R>
+{
+    async fn create_link_table(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn list_link(&self) -> Result<Vec<Link>> {
+        Ok(links_of(&self.list_note().await?))
+    }
+}
@@ -0,0 +1,509 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use itertools::Itertools;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{
+    apply_filter, links_of, Attachment, AttachmentRepository, Initable, Link, LinkRepository, Note,
+    NoteRepository, Project, ProjectRepository, Transactional,
+};
+
+const FRONT_MATTER: &str = "+++";
+pub(crate) const PROJECT_FILE: &str = "project.toml";
+pub(crate) const ATTACHMENTS_FILE: &str = "attachments.toml";
+
+/// The attachments of all the notes, kept in one file at the root
+#[derive(Default, Serialize, Deserialize)]
+struct Attachments {
+    #[serde(default)]
+    attachment: Vec<Attachment>,
+}
+
+/// Stores every project as a directory and every note as a Markdown file with front matter in it
+pub struct FilesRepository {
+    root: PathBuf,
+    initialized: AtomicBool,
+    /// Directories of the projects inserted since begin, None outside of a transaction
+    inserted: Mutex<Option<Vec<PathBuf>>>,
+}
+
+impl FilesRepository {
+    pub(crate) async fn open(root: PathBuf) -> Result<FilesRepository> {
+        let repo = FilesRepository {
+            root,
+            initialized: AtomicBool::new(false),
+            inserted: Mutex::new(None),
+        };
+        repo.init().await?;
+        Ok(repo)
+    }
+
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    /// Directories holding a project file, nested projects included
+    fn project_dirs(&self) -> Result<Vec<PathBuf>> {
+        let mut dirs = vec![];
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            if dir.join(PROJECT_FILE).exists() {
+                dirs.push(dir.clone());
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    pending.push(path);
+                }
+            }
+        }
+        Ok(dirs.into_iter().sorted().collect_vec())
+    }
+
+    fn read_project(dir: &Path) -> Result<Project> {
+        Ok(toml::from_str(&fs::read_to_string(
+            dir.join(PROJECT_FILE),
+        )?)?)
+    }
+
+    fn project_dir(&self, project_id: &str) -> Result<PathBuf> {
+        for dir in self.project_dirs()? {
+            if Self::read_project(&dir)?.guid() == project_id {
+                return Ok(dir);
+            }
+        }
+        Err(anyhow!("Project {project_id} does not exist"))
+    }
+
+    fn note_files(dir: &Path) -> Result<Vec<PathBuf>> {
+        Ok(fs::read_dir(dir)?
+            .map(|entry| entry.map(|it| it.path()))
+            .filter_ok(|path| path.extension().is_some_and(|it| it == "md"))
+            .collect::<std::io::Result<Vec<_>>>()?
+            .into_iter()
+            .sorted()
+            .collect_vec())
+    }
+
+    fn note_file(&self, key: &str) -> Result<Option<PathBuf>> {
+        for dir in self.project_dirs()? {
+            let path = dir.join(format!("{key}.md"));
+            if path.exists() {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Take the next number of the project in dir, a project file written by an older version
+    /// has no counter and starts after the highest number in use
+    fn take_number(dir: &Path) -> Result<i64> {
+        let project = Self::read_project(dir)?;
+        let mut number = project.next_number();
+        for file in Self::note_files(dir)? {
+            number = number.max(read_note(&file)?.number() + 1);
+        }
+        fs::write(
+            dir.join(PROJECT_FILE),
+            toml::to_string(&project.with_next_number(number + 1))?,
+        )?;
+        Ok(number)
+    }
+
+    fn read_attachments(&self) -> Result<Vec<Attachment>> {
+        let path = self.root.join(ATTACHMENTS_FILE);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        parse_attachments(&fs::read_to_string(path)?)
+    }
+
+    /// Drop the attachments of notes that were removed, like the cascade of the sqlite backend
+    fn prune_attachments(&self) -> Result<()> {
+        let attachments = self.read_attachments()?;
+        let mut kept = vec![];
+        for attachment in attachments {
+            if self.note_file(attachment.note_id())?.is_some() {
+                kept.push(attachment);
+            }
+        }
+        write_attachments(&self.root, kept)
+    }
+
+    /// Write the note into the directory of its project, moving it if the project changed
+    fn write_note(&self, entity: Note, previous: Option<PathBuf>) -> Result<()> {
+        let dir = self.project_dir(entity.project_id())?;
+        let path = dir.join(format!("{}.md", entity.guid()));
+        let entity = match &previous {
+            Some(previous) if previous.parent() == Some(dir.as_path()) => entity,
+            _ => entity.with_number(Self::take_number(&dir)?),
+        };
+        fs::write(&path, write_front_matter(&entity)?)?;
+        if let Some(previous) = previous {
+            if previous != path {
+                fs::remove_file(previous)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serialize a note to a Markdown file with toml front matter
+pub(crate) fn write_front_matter(note: &Note) -> Result<String> {
+    Ok(format!(
+        "{FRONT_MATTER}\n{}{FRONT_MATTER}\n{}",
+        toml::to_string(note)?,
+        note.content()
+    ))
+}
+
+/// Parse a Markdown file with toml front matter back to a note
+pub(crate) fn read_front_matter(text: &str) -> Result<Note> {
+    let rest = text
+        .strip_prefix(FRONT_MATTER)
+        .ok_or(anyhow!("Missing front matter"))?;
+    let (front_matter, content) = rest
+        .split_once(&format!("\n{FRONT_MATTER}\n"))
+        .ok_or(anyhow!("Unterminated front matter"))?;
+    let note: Note = toml::from_str(front_matter)?;
+    Ok(note.with_content(content.to_string()))
+}
+
+/// Parse the content of an attachments file
+pub(crate) fn parse_attachments(text: &str) -> Result<Vec<Attachment>> {
+    let attachments: Attachments = toml::from_str(text)?;
+    Ok(attachments.attachment)
+}
+
+/// Write the attachments file of a notes directory
+pub(crate) fn write_attachments(root: &Path, attachments: Vec<Attachment>) -> Result<()> {
+    fs::write(
+        root.join(ATTACHMENTS_FILE),
+        toml::to_string(&Attachments {
+            attachment: attachments,
+        })?,
+    )?;
+    Ok(())
+}
+
+fn read_note(path: &Path) -> Result<Note> {
+    read_front_matter(&fs::read_to_string(path)?)
+        .map_err(|err| anyhow!("Invalid note {}: {err}", path.display()))
+}
+
+/// Files are written as they go, rollback only takes back the projects inserted since begin
+/// that are still empty, written notes stay as they are
+#[async_trait]
+impl Transactional for FilesRepository {
+    async fn begin(&self) -> Result<()> {
+        let mut inserted = self.inserted.lock().unwrap();
+        if inserted.is_some() {
+            return Err(anyhow!("A transaction is already open"));
+        }
+        *inserted = Some(vec![]);
+        Ok(())
+    }
+
+    async fn commit(&self) -> Result<()> {
+        self.inserted
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(anyhow!("No transaction is open"))?;
+        Ok(())
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        let inserted = self
+            .inserted
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(anyhow!("No transaction is open"))?;
+        // Children were inserted after their parents
+        for dir in inserted.iter().rev() {
+            if Self::note_files(dir)?.is_empty() {
+                fs::remove_file(dir.join(PROJECT_FILE))?;
+                let _ = fs::remove_dir(dir);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Initable for FilesRepository {
+    fn initialized(&self) -> bool {
+        self.initialized.load(Ordering::Acquire)
+    }
+
+    async fn init(&self) -> Result<()> {
+        if !self.initialized() {
+            self.create_proj_table().await?;
+            self.create_note_table().await?;
+            self.create_attachment_table().await?;
+        }
+        self.initialized.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NoteRepository for FilesRepository {
+    async fn create_note_table(&self) -> Result<()> {
+        fs::create_dir_all(&self.root)?;
+        Ok(())
+    }
+
+    async fn insert_note(&self, entity: Note) -> Result<()> {
+        match self.note_file(entity.guid())? {
+            Some(path) => {
+                let note = read_note(&path)?;
+                let updated = Note::new(
+                    note.guid().to_owned(),
+                    entity.project_id().to_owned(),
+                    entity.name().to_string(),
+                    entity.content().to_string(),
+                    entity.ts(),
+                )
+                .with_number(note.number())
+                .with_author(entity.author().to_string())
+                .with_due(entity.due())
+                .with_pinned(entity.pinned())
+                .with_starred(entity.starred())
+                .with_status(entity.status().to_string())
+                .with_modified(entity.modified());
+                self.write_note(updated, Some(path))
+            }
+            None => self.write_note(entity, None),
+        }
+    }
+
+    async fn remove_note(&self, key: String) -> Result<u32> {
+        match self.note_file(&key)? {
+            Some(path) => {
+                fs::remove_file(path)?;
+                self.prune_attachments()?;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn get_note(&self, key: String) -> Result<Note> {
+        let path = self
+            .note_file(&key)?
+            .ok_or(anyhow!("Note {key} does not exist"))?;
+        read_note(&path)
+    }
+
+    async fn list_note(&self) -> Result<Vec<Note>> {
+        let mut items = vec![];
+        for dir in self.project_dirs()? {
+            let mut notes = vec![];
+            for file in Self::note_files(&dir)? {
+                notes.push(read_note(&file)?);
+            }
+            items.extend(notes.into_iter().sorted_by_key(|it| it.number()));
+        }
+        Ok(items)
+    }
+
+    async fn list_note_with_filter<T: Fn(&Note) -> bool + Send + Sync>(
+        &self,
+        pred: T,
+    ) -> Result<Vec<Note>> {
+        let items = self.list_note().await?;
+        Ok(apply_filter(pred, items))
+    }
+
+    async fn update_note(&self, key: String, text: String, project_id: String) -> Result<u64> {
+        match self.note_file(&key)? {
+            Some(path) => {
+                let note = read_note(&path)?
+                    .with_content(text)
+                    .with_project_id(project_id)
+                    .with_modified(Utc::now().naive_utc());
+                self.write_note(note, Some(path))?;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn remove_notes(&self, keys: Vec<String>) -> Result<u32> {
+        // Check every note first so that a missing one leaves all of them in place
+        let mut paths = vec![];
+        for key in keys {
+            paths.push(
+                self.note_file(&key)?
+                    .ok_or(anyhow!("Note {key} does not exist"))?,
+            );
+        }
+        for path in &paths {
+            fs::remove_file(path)?;
+        }
+        self.prune_attachments()?;
+        Ok(paths.len() as u32)
+    }
+
+    async fn update_notes(&self, entities: Vec<Note>) -> Result<u64> {
+        let mut pending = vec![];
+        for entity in entities {
+            let path = self
+                .note_file(entity.guid())?
+                .ok_or(anyhow!("Note {} does not exist", entity.guid()))?;
+            self.project_dir(entity.project_id())?;
+            pending.push((entity, path));
+        }
+        let count = pending.len() as u64;
+        for (entity, path) in pending {
+            let note = read_note(&path)?
+                .with_content(entity.content().to_string())
+                .with_project_id(entity.project_id().to_owned())
+                .with_due(entity.due())
+                .with_pinned(entity.pinned())
+                .with_starred(entity.starred())
+                .with_status(entity.status().to_string())
+                .with_modified(Utc::now().naive_utc());
+            self.write_note(note, Some(path))?;
+        }
+        Ok(count)
+    }
+}
+
+#[async_trait]
+impl ProjectRepository for FilesRepository {
+    async fn create_proj_table(&self) -> Result<()> {
+        fs::create_dir_all(&self.root)?;
+        Ok(())
+    }
+
+    async fn insert_project(&self, entity: Project) -> Result<()> {
+        if self
+            .list_project()
+            .await?
+            .iter()
+            .any(|it| it.name() == entity.name())
+        {
+            return Err(anyhow!("Project {} already exists", entity.name()));
+        }
+        let dir = self.root.join(entity.name());
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(PROJECT_FILE), toml::to_string(&entity)?)?;
+        if let Some(inserted) = self.inserted.lock().unwrap().as_mut() {
+            inserted.push(dir);
+        }
+        Ok(())
+    }
+
+    async fn remove_project(&self, key: String) -> Result<u32> {
+        match self.project_dir(&key) {
+            Ok(dir) => {
+                // The notes of the project go with it, like the rows of the sqlite backend
+                for file in Self::note_files(&dir)? {
+                    fs::remove_file(file)?;
+                }
+                fs::remove_file(dir.join(PROJECT_FILE))?;
+                // Nested projects keep the directory
+                let _ = fs::remove_dir(dir);
+                self.prune_attachments()?;
+                Ok(1)
+            }
+            Err(_) => Ok(0),
+        }
+    }
+
+    async fn update_project(&self, entity: Project) -> Result<u64> {
+        match self.project_dir(entity.guid()) {
+            Ok(dir) => {
+                let next_number = Self::read_project(&dir)?.next_number();
+                fs::write(
+                    dir.join(PROJECT_FILE),
+                    toml::to_string(&entity.with_next_number(next_number))?,
+                )?;
+                Ok(1)
+            }
+            Err(_) => Ok(0),
+        }
+    }
+
+    async fn get_project(&self, key: String) -> Result<Project> {
+        Self::read_project(&self.project_dir(&key)?)
+    }
+
+    async fn list_project(&self) -> Result<Vec<Project>> {
+        self.project_dirs()?
+            .iter()
+            .map(|dir| Self::read_project(dir))
+            .collect()
+    }
+
+    async fn list_project_with_filter<T: Fn(&Project) -> bool + Send + Sync>(
+        &self,
+        pred: T,
+    ) -> Result<Vec<Project>> {
+        let items = self.list_project().await?;
+        Ok(apply_filter(pred, items))
+    }
+}
+
+#[async_trait]
+impl AttachmentRepository for FilesRepository {
+    async fn create_attachment_table(&self) -> Result<()> {
+        fs::create_dir_all(&self.root)?;
+        Ok(())
+    }
+
+    async fn insert_attachment(&self, entity: Attachment) -> Result<()> {
+        if self.note_file(entity.note_id())?.is_none() {
+            return Err(anyhow!("Note {} does not exist", entity.note_id()));
+        }
+        let mut attachments = self.read_attachments()?;
+        attachments.retain(|it| it.guid() != entity.guid());
+        attachments.push(entity);
+        write_attachments(&self.root, attachments)
+    }
+
+    async fn remove_attachment(&self, key: String) -> Result<u32> {
+        let mut attachments = self.read_attachments()?;
+        let count = attachments.len();
+        attachments.retain(|it| *it.guid() != key);
+        let removed = (count - attachments.len()) as u32;
+        write_attachments(&self.root, attachments)?;
+        Ok(removed)
+    }
+
+    async fn list_attachment(&self) -> Result<Vec<Attachment>> {
+        self.read_attachments()
+    }
+
+    async fn list_attachment_with_filter<T: Fn(&Attachment) -> bool + Send + Sync>(
+        &self,
+        pred: T,
+    ) -> Result<Vec<Attachment>> {
+        let items = self.list_attachment().await?;
+        Ok(apply_filter(pred, items))
+    }
+}
+
+/// Links are read from the content of the notes, there is nothing to keep up to date
+#[async_trait]
+impl LinkRepository for FilesRepository {
+    async fn create_link_table(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn list_link(&self) -> Result<Vec<Link>> {
+        Ok(links_of(&self.list_note().await?))
+    }
+}
@@ -1,23 +1,46 @@
 use std::ffi::OsStr;
+use std::io::{stdin, stdout, IsTerminal};
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 use anyhow::{anyhow, Result};
-use chrono::Utc;
-use clap::{Parser, Subcommand};
+use chrono::{Duration, Utc};
+use clap::{Args, Parser, Subcommand};
+use crossterm::style::{Color, Stylize};
 use itertools::Itertools;
 use uuid::Uuid;
 
 use commands::*;
 
-use crate::backend::{Note, NoteRepository, Project, ProjectRepository};
+use crate::attachments::BlobStore;
+use crate::backend::{
+    attachment_label, check_project_name, finish, parse_checkboxes, tick_checkbox, Attachment,
+    AttachmentRepository, LinkRepository, Note, NoteRepository, Project, ProjectRepository,
+    Transactional,
+};
 use crate::config::Config;
+use crate::encrypted::{
+    open_secret, seal_secret, secret_passphrase, EncryptedRepository, KEY_CHECK_FILE,
+};
+use crate::files::FilesRepository;
+use crate::picker::{pick_note, pick_project};
+use crate::postgres::PostgresRepository;
 use crate::sqlite::SqliteRepository;
+use crate::sync::GitSync;
 
+mod attachments;
 pub mod backend;
 mod commands;
 mod config;
+#[cfg(test)]
+mod conformance;
+pub mod encrypted;
+pub mod files;
+mod picker;
+pub mod postgres;
+mod sql;
 pub mod sqlite;
+mod sync;
 
 /// Simple program to add dev notes
 #[derive(Parser, Debug)]
@@ -26,14 +49,17 @@ struct Varargs {
     ///The command to be run
     #[command(subcommand)]
     mode: CommandMode,
+    /// Override a setting for this run, e.g. --config backend=files
+    #[arg(long = "config", global = true, value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 enum CommandMode {
     /// Use a project to add the notes to
     Use {
-        /// The project name to be appended
-        project: String,
+        /// The project name to be appended, picked interactively if omitted
+        project: Option<String>,
     },
     ///Add Dev note to selected project
     Add {
@@ -42,57 +68,389 @@ enum CommandMode {
 
         /// Optional: Date of note
         date: Option<String>,
+
+        /// Encrypt the note with a passphrase of its own
+        #[arg(long)]
+        secret: bool,
+        /// Follow the note up by then, YYYY-MM-DD, YYYY-MM-DD HH:MM or +<n>h, +<n>d, +<n>w
+        #[arg(long)]
+        due: Option<String>,
+        /// Status of the note, one of the statuses of the configuration
+        #[arg(long)]
+        status: Option<String>,
     },
-    ///Delete note from project
+    ///Delete notes from project
     #[command(name = "rm")]
     Delete {
-        /// Guid prefix of the note to delete
-        id: String,
+        #[command(flatten)]
+        selection: NoteSelection,
     },
     /// List notes for current project
     #[command(name = "ls")]
     List {
         #[arg(name = "g", short, long, action = clap::ArgAction::SetTrue)]
         no_guid: bool,
+        /// List the notes of every project
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        all: bool,
+        /// List the notes of these projects instead of the selected one
+        #[arg(short, long)]
+        project: Vec<String>,
+        /// Only list the notes whose author contains this text
+        #[arg(long)]
+        author: Option<String>,
+        /// Only list the starred notes
+        #[arg(long)]
+        starred: bool,
+        /// List the notes of the projects nested in the listed ones as well
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only list the notes with this status, "none" for the ones without any
+        #[arg(long)]
+        status: Option<String>,
+    },
+    /// List the latest notes of every project
+    Recent {
+        /// How many notes to list
+        #[arg(short, long, default_value_t = 10)]
+        number: usize,
+        #[arg(name = "g", short, long, action = clap::ArgAction::SetTrue)]
+        no_guid: bool,
     },
     /// List selectable Projects
-    Projects,
+    Projects {
+        /// Show the description, path and defaults of the projects
+        #[arg(short, long)]
+        long: bool,
+    },
+    /// Read and change the settings
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Manage a project
+    Project {
+        #[command(subcommand)]
+        action: ProjectAction,
+    },
     /// View note
     View {
-        /// id of the note
-        guid: String,
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
         #[arg(name = "g", short, long, action = clap::ArgAction::SetTrue)]
         no_guid: bool,
     },
-    /// Edit note
+    /// Edit notes one after the other
     Edit {
-        /// id of the note
+        #[command(flatten)]
+        selection: NoteSelection,
+        /// Set the due date of the notes instead of editing them, "none" to clear it
+        #[arg(long)]
+        due: Option<String>,
+        /// Set the status of the notes instead of editing them, "none" to clear it
+        #[arg(long)]
+        status: Option<String>,
+    },
+    /// Set the status of a note, one of the statuses of the configuration or "none"
+    Status {
+        /// Guid prefix, #number or project#number of the note
         guid: String,
+        state: String,
+    },
+    /// Always list a note before the others of its project
+    Pin {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
     },
-    /// Moves note with guid prefix to current project
+    /// List a pinned note along with the others again
+    Unpin {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
+    },
+    /// Mark a note as a favourite
+    Star {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
+    },
+    /// Remove a note from the favourites
+    Unstar {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
+    },
+    /// List the overdue notes and the ones due soon, exits with status 1 if any is overdue
+    Due {
+        /// How many days ahead to look
+        #[arg(short, long, default_value_t = 7)]
+        days: i64,
+    },
+    /// Moves notes to current project or the given one
     Move {
-        /// id of the note
+        #[command(flatten)]
+        selection: NoteSelection,
+        #[command(flatten)]
+        target: TargetProject,
+    },
+    /// Maintain the sqlite database
+    Db {
+        #[command(subcommand)]
+        action: DbAction,
+    },
+    /// Synchronize the notebook with a git remote, not available on an encrypted notebook
+    Sync {
+        #[command(subcommand)]
+        action: SyncAction,
+    },
+    /// Copies notes with fresh ids to current project or the given one
+    Copy {
+        #[command(flatten)]
+        selection: NoteSelection,
+        #[command(flatten)]
+        target: TargetProject,
+    },
+    /// Attach a file to a note, not available on an encrypted notebook
+    Attach {
+        /// Guid prefix, #number or project#number of the note
+        guid: String,
+        /// The file to attach
+        file: PathBuf,
+    },
+    /// List the files attached to a note
+    Attachments {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
+    },
+    /// Write a file attached to a note
+    Extract {
+        /// Guid prefix, #number or project#number of the note
         guid: String,
+        /// Name of the attachment
+        name: String,
+        /// File or directory to write to, the current directory by default
+        dest: Option<PathBuf>,
+    },
+    /// List the notes a note links to with [[id]] or [[name]]
+    Links {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
+        /// List the links of all the notes that point to no note instead
+        #[arg(long)]
+        broken: bool,
+    },
+    /// List the notes linking to a note
+    Backlinks {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
+    },
+    /// List the open "- [ ]" items of the notes of the project
+    Todo {
+        #[command(subcommand)]
+        action: Option<TodoAction>,
+    },
+    /// Lock a note with a passphrase of its own
+    Encrypt {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
+    },
+    /// Store a secret note in plain text again
+    Decrypt {
+        /// Guid prefix, #number or project#number of the note, picked interactively if omitted
+        guid: Option<String>,
+    },
+    /// Manage the encryption of the whole notebook
+    Encryption {
+        #[command(subcommand)]
+        action: EncryptionAction,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ConfigAction {
+    /// Print the value of a setting once every layer is applied
+    Get { key: String },
+    /// Change a setting in config.toml
+    Set {
+        key: String,
+        /// The value, lists are separated by commas
+        value: String,
+        /// Change it in the .devnotes.toml of the project instead, which only takes the
+        /// presentation settings and the defaults of new notes
+        #[arg(long)]
+        project: bool,
+    },
+    /// List every setting with its value and where it comes from
+    List,
+    /// Open config.toml in the editor and check it afterwards
+    Edit,
+    /// Print the paths of the configuration files
+    Path,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ProjectAction {
+    /// Set the description, path, colour and defaults of a project, an empty value clears one
+    Edit {
+        /// The project name
+        name: String,
+        #[arg(long)]
+        description: Option<String>,
+        /// Directory or repository the project is about
+        #[arg(long)]
+        path: Option<String>,
+        /// Colour of the name in listings, e.g. red or dark_cyan
+        #[arg(long)]
+        colour: Option<String>,
+        /// Name of new notes instead of default_name of the configuration
+        #[arg(long)]
+        default_name: Option<String>,
+        /// File whose content new notes start with, kept in config.toml of this machine
+        #[arg(long)]
+        template: Option<String>,
+        /// Editor of new notes instead of edit_app, kept in config.toml of this machine
+        #[arg(long)]
+        editor: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum TodoAction {
+    /// Tick an item of a note
+    Done {
+        /// The item as <note>#<n>, e.g. #2#1 or api#2#1 for the first item of note 2
+        item: String,
     },
 }
 
+#[derive(Subcommand, Debug, Clone)]
+enum DbAction {
+    /// Copy the database to a file
+    Backup {
+        /// File to write the backup to, must not exist. There is no default, the data directory
+        /// sits next to the program and is wiped along with it on reinstall
+        path: PathBuf,
+    },
+    /// Replace the database with a backup
+    Restore {
+        /// The backup file
+        path: PathBuf,
+        /// Do not ask for confirmation
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        yes: bool,
+    },
+    /// Rebuild the database file to reclaim unused space
+    Vacuum,
+    /// Run the integrity check and look for notes without a project
+    Check,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum SyncAction {
+    /// Set up synchronization with a git remote, e.g. a path to a bare repository
+    Init {
+        /// The git remote to synchronize with
+        remote: String,
+    },
+    /// Merge the notes of the remote into the notebook and push the result
+    Push,
+    /// Merge the notes of the remote into the notebook
+    Pull,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum EncryptionAction {
+    /// Decrypt every note, forget the key and turn encrypt off in config.toml
+    Disable {
+        /// Do not ask for confirmation
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        yes: bool,
+    },
+}
+
+/// Project that notes are moved or copied into
+#[derive(Args, Debug, Clone)]
+struct TargetProject {
+    /// Name of the target project, the selected one if omitted
+    #[arg(long)]
+    to: Option<String>,
+    /// Create the target project if it does not exist
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    create: bool,
+}
+
+/// Notes to run a command on, by reference and/or by query over the selected project
+#[derive(Args, Debug, Clone)]
+struct NoteSelection {
+    /// Guid prefixes, #numbers or project#numbers of the notes, picked interactively if omitted
+    ids: Vec<String>,
+    /// Select notes dated before this day (YYYY-MM-DD)
+    #[arg(long)]
+    before: Option<String>,
+    /// Select notes dated on or after this day (YYYY-MM-DD)
+    #[arg(long)]
+    after: Option<String>,
+    /// Select notes with this name
+    #[arg(long)]
+    name: Option<String>,
+    /// Select notes whose content contains this text
+    #[arg(long)]
+    contains: Option<String>,
+    /// Select notes whose content contains this #tag
+    #[arg(long)]
+    tag: Option<String>,
+    /// Only list the notes that would be affected
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    dry_run: bool,
+    /// Do not ask for confirmation
+    #[arg(short, long, action = clap::ArgAction::SetTrue)]
+    yes: bool,
+}
+
+impl NoteSelection {
+    fn is_query(&self) -> bool {
+        self.before.is_some()
+            || self.after.is_some()
+            || self.name.is_some()
+            || self.contains.is_some()
+            || self.tag.is_some()
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Varargs::parse();
     let binding = env::current_exe()?;
     let exe_path = binding.parent().ok_or(anyhow!("bitch"))?;
-    let config =
-        config::get_or_create_config(&exe_path).expect("Config did not exist...Default Created");
-    let selected = (&exe_path).join("selected.txt").to_owned();
+    if let CommandMode::Config { action } = args.mode {
+        return run_config(action, exe_path, &args.overrides);
+    }
+    // The first run on a terminal sets up the configuration, scripts keep the defaults
+    let user_config = config::user_config_path(exe_path);
+    if !user_config.exists() && stdin().is_terminal() && stdout().is_terminal() {
+        setup_wizard(&user_config)?;
+    }
+    let config = config::load_config(exe_path, &args.overrides)?;
+    let selected = exe_path.join("selected.txt").to_owned();
     let selected_proj = if !Path::new(&selected).exists() {
         fs::write(&selected, "")?;
         "".to_string()
     } else {
         fs::read_to_string(&selected)?
     };
-    let mut repo = SqliteRepository::default().await?;
     if let CommandMode::Use { .. } = args.mode {
-    } else if let CommandMode::Projects = args.mode {
+    } else if let CommandMode::Projects { .. } = args.mode {
+    } else if let CommandMode::Project { .. } = args.mode {
+    } else if let CommandMode::Recent { .. } = args.mode {
+    } else if let CommandMode::Sync { .. } = args.mode {
+    } else if let CommandMode::Db { .. } = args.mode {
+    } else if let CommandMode::Encryption { .. } = args.mode {
+    } else if let CommandMode::Due { .. } = args.mode {
+    } else if let CommandMode::Links { broken: true, .. } = args.mode {
+    } else if let CommandMode::List { all: true, .. } = args.mode {
+    } else if let CommandMode::List { project, .. } = &args.mode {
+        if project.is_empty() && selected_proj.is_empty() {
+            return Err(anyhow!(
+                "No project selected please run with the \"use <proj_name>\" command first"
+            ));
+        }
     } else {
         if selected_proj.is_empty() {
             return Err(anyhow!(
@@ -100,8 +458,256 @@ async fn main() -> Result<()> {
             ));
         }
     }
-    match args.mode {
+    let data_dir = config.data_dir(exe_path);
+    fs::create_dir_all(&data_dir)?;
+    match config.backend().as_str() {
+        "" | "sqlite" => {
+            let repo = SqliteRepository::open(&data_dir.join("note.db")).await?;
+            if let CommandMode::Db { action } = args.mode {
+                return run_db(repo, action, &data_dir).await;
+            }
+            run_unlocked(repo, args.mode, config, exe_path, selected, selected_proj).await
+        }
+        "files" => {
+            let repo = FilesRepository::open(data_dir.join("notes")).await?;
+            run_unlocked(repo, args.mode, config, exe_path, selected, selected_proj).await
+        }
+        "postgres" => {
+            let url = config::string_optional(config.database_url().to_owned()).ok_or(anyhow!(
+                "The postgres backend needs a database_url in config.toml"
+            ))?;
+            let repo = PostgresRepository::connect(&url).await?;
+            run_unlocked(repo, args.mode, config, exe_path, selected, selected_proj).await
+        }
+        backend => Err(anyhow!(
+            "Unknown backend {backend}, expected sqlite, files or postgres"
+        )),
+    }
+}
+
+/// Run a configuration command, they work with invalid settings too so that those can be fixed
+fn run_config(action: ConfigAction, exe_path: &Path, overrides: &[String]) -> Result<()> {
+    match action {
+        ConfigAction::Get { key } => {
+            let layers = config::layers(exe_path, overrides)?;
+            let value = layers
+                .iter()
+                .rev()
+                .find_map(|it| it.table.get(&key))
+                .ok_or(anyhow!("Unknown key {key}"))?;
+            match value.as_str() {
+                Some(value) => println!("{value}"),
+                None => println!("{value}"),
+            }
+        }
+        ConfigAction::Set {
+            key,
+            value,
+            project,
+        } => {
+            let path = if project {
+                let path = match config::project_config_path() {
+                    Some(path) => path,
+                    None => env::current_dir()?.join(config::PROJECT_CONFIG_FILE),
+                };
+                config::check_project_key(&key, &path.display().to_string())?;
+                path
+            } else {
+                config::user_config_path(exe_path)
+            };
+            config::set_value(&path, &key, &value)?;
+            println!("Set {key} in {}", path.display());
+        }
+        ConfigAction::List => {
+            let layers = config::layers(exe_path, overrides)?;
+            for (key, _) in &layers[0].table {
+                let layer = layers
+                    .iter()
+                    .rev()
+                    .find(|it| it.table.contains_key(key))
+                    .unwrap();
+                println!("{key} = {} # {}", layer.table[key], layer.source);
+            }
+        }
+        ConfigAction::Edit => {
+            let path = config::user_config_path(exe_path);
+            if !path.exists() {
+                fs::write(&path, toml::to_string(&Config::default())?)?;
+            }
+            let editor = config::load_config(exe_path, overrides)
+                .ok()
+                .and_then(|it| config::string_optional(it.edit_app().to_owned()))
+                .filter(|it| it != "interna")
+                .or_else(|| env::var("VISUAL").ok())
+                .or_else(|| env::var("EDITOR").ok())
+                .ok_or(anyhow!(
+                    "No editor to open {} with, set edit_app or $EDITOR",
+                    path.display()
+                ))?;
+            std::process::Command::new(editor).arg(&path).status()?;
+            config::read_layer(&path)?;
+            println!("{} is valid", path.display());
+        }
+        ConfigAction::Path => {
+            println!("{}", config::user_config_path(exe_path).display());
+            if let Some(path) = config::project_config_path() {
+                println!("{}", path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run a database maintenance command
+async fn run_db(repo: SqliteRepository, action: DbAction, data_dir: &Path) -> Result<()> {
+    match action {
+        DbAction::Backup { path } => {
+            repo.backup(&path).await?;
+            println!("Backed up to {}", path.display());
+            // Encrypted notes can only be read back with the salt they were sealed with
+            let key_check = data_dir.join(KEY_CHECK_FILE);
+            if key_check.exists() {
+                let copy = path.with_extension(KEY_CHECK_FILE);
+                fs::copy(&key_check, &copy)?;
+                println!("Backed up the encryption salt to {}", copy.display());
+            }
+            // The content of the attachments lives outside of the database
+            let attachments = repo.list_attachment().await?;
+            if !attachments.is_empty() {
+                let dir = path.with_extension("attachments");
+                BlobStore::new(data_dir.join("attachments"))
+                    .copy_to(&attachments.iter().map(|it| it.hash()).collect_vec(), &dir)?;
+                println!("Backed up attachments to {}", dir.display());
+            }
+        }
+        DbAction::Restore { path, yes } => {
+            if !yes && !confirm(&format!("Replace all notes with {}?", path.display()))? {
+                return Ok(());
+            }
+            repo.restore(&path).await?;
+            println!("Restored from {}", path.display());
+            let key_check = path.with_extension(KEY_CHECK_FILE);
+            if key_check.exists() {
+                fs::copy(&key_check, data_dir.join(KEY_CHECK_FILE))?;
+                println!("Restored the encryption salt, unlock with the passphrase of the backup");
+            }
+            let dir = path.with_extension("attachments");
+            if dir.exists() {
+                let attachments = repo.list_attachment().await?;
+                let copied = BlobStore::new(dir).copy_to(
+                    &attachments.iter().map(|it| it.hash()).collect_vec(),
+                    &data_dir.join("attachments"),
+                )?;
+                println!("Restored {copied} attachments");
+            }
+        }
+        DbAction::Vacuum => {
+            repo.vacuum().await?;
+            println!("Vacuumed");
+        }
+        DbAction::Check => {
+            let problems = repo.check().await?;
+            for problem in &problems {
+                println!("{problem}");
+            }
+            if !problems.is_empty() {
+                return Err(anyhow!("Found {} problems", problems.len()));
+            }
+            println!("No problems found");
+        }
+    }
+    Ok(())
+}
+
+/// Run the command through the encryption of the notebook if it is enabled,
+/// a notebook is only decrypted again by "encryption disable"
+async fn run_unlocked<
+    R: NoteRepository
+        + ProjectRepository
+        + AttachmentRepository
+        + LinkRepository
+        + Transactional
+        + Send
+        + Sync,
+>(
+    repo: R,
+    mode: CommandMode,
+    config: Config,
+    exe_path: &Path,
+    selected: PathBuf,
+    selected_proj: String,
+) -> Result<()> {
+    let key_check = config.data_dir(exe_path).join(KEY_CHECK_FILE);
+    let key_file = config.key_file(exe_path);
+    // The working tree of sync and the attachment store hold plain files
+    let plain_text = match mode {
+        CommandMode::Sync { .. } => Some("Sync would write the notes in plain text"),
+        CommandMode::Attach { .. } => Some("Attachments are stored in plain text"),
+        _ => None,
+    };
+    if let Some(reason) = plain_text.filter(|_| config.encrypt() || key_check.exists()) {
+        return Err(anyhow!(
+            "{reason}, it is not available on an encrypted notebook"
+        ));
+    }
+    if let CommandMode::Encryption {
+        action: EncryptionAction::Disable { yes },
+    } = mode
+    {
+        if !key_check.exists() {
+            return Err(anyhow!("The notebook is not encrypted"));
+        }
+        if !yes && !confirm("Store every note in plain text?")? {
+            return Ok(());
+        }
+        EncryptedRepository::unlock(repo, key_check, key_file)
+            .await?
+            .into_plain()
+            .await?;
+        let path = config::user_config_path(exe_path);
+        config::set_value(&path, "encrypt", "false")?;
+        println!("Set encrypt to false in {}", path.display());
+        Ok(())
+    } else if config.encrypt() && config.backend() == "postgres" {
+        // The salt is kept in the data directory of each machine while the notes are shared,
+        // every client would encrypt with a key of its own
+        Err(anyhow!(
+            "encrypt is not available with the postgres backend, the key is kept on each machine while the notes are shared"
+        ))
+    } else if config.encrypt() {
+        let repo = EncryptedRepository::unlock(repo, key_check, key_file).await?;
+        run(repo, mode, config, exe_path, selected, selected_proj).await
+    } else if key_check.exists() {
+        Err(anyhow!(
+            "The notebook is encrypted but encrypt is off, turn it back on or run \"encryption disable\" to decrypt it"
+        ))
+    } else {
+        run(repo, mode, config, exe_path, selected, selected_proj).await
+    }
+}
+
+/// Run the command against the repository of the configured backend
+async fn run<
+    R: NoteRepository
+        + ProjectRepository
+        + AttachmentRepository
+        + LinkRepository
+        + Transactional
+        + Send,
+>(
+    repo: R,
+    mode: CommandMode,
+    config: Config,
+    exe_path: &Path,
+    selected: PathBuf,
+    selected_proj: String,
+) -> Result<()> {
+    match mode {
         CommandMode::Use { project } => {
+            let project = match project {
+                Some(project) => project,
+                None => pick_project(&repo.list_project().await?)?.name().to_owned(),
+            };
             println!(
                 "Project: {}",
                 if !selected_proj.is_empty() {
@@ -111,20 +717,27 @@ async fn main() -> Result<()> {
                 }
             );
 
-            if let Ok(projs) = repo.list_project_with_filter(match_name(&project)).await {
-                if projs.is_empty() {
-                    repo.insert_project(Project::new(
-                        Uuid::new_v4().to_string(),
-                        project.to_string(),
-                        Utc::now().naive_utc(),
-                    ))
-                    .await?
+            repo.begin().await?;
+            let created = async {
+                if let Ok(projs) = repo.list_project_with_filter(match_name(&project)).await {
+                    if projs.is_empty() {
+                        create_project(&repo, &project).await?;
+                    }
                 }
+                Ok(())
             }
+            .await;
+            finish(&repo, created).await?;
             fs::write(&selected, &project)?;
             println!("Using Project: {project}")
         }
-        CommandMode::Add { name, date } => {
+        CommandMode::Add {
+            name,
+            date,
+            secret,
+            due,
+            status,
+        } => {
             println!(
                 "Project: {}",
                 if !selected_proj.is_empty() {
@@ -133,9 +746,6 @@ async fn main() -> Result<()> {
                     "Not Selected"
                 }
             );
-            let default_name = config.default_name().to_owned();
-            let final_name = name.or_else(|| config::string_optional(default_name));
-
             if selected_proj.is_empty() {
                 return Err(anyhow!(
                     "No project selected please run with the \"use <proj_name>\" command first"
@@ -148,31 +758,134 @@ async fn main() -> Result<()> {
                 .first()
                 .unwrap()
                 .to_owned();
+            // The defaults of the project come before the ones of the configuration
+            let default_name = config::string_optional(selected_projjj.default_name().to_string())
+                .unwrap_or(config.default_name().to_owned());
+            let final_name = name.or_else(|| config::string_optional(default_name));
+            let settings = config.project(&selected_proj);
+            let template = match config::string_optional(settings.template().to_string()) {
+                Some(template) => Some(
+                    fs::read_to_string(&template)
+                        .map_err(|err| anyhow!("Cannot read the template {template}: {err}"))?,
+                ),
+                None => None,
+            };
+            let config = match config::string_optional(settings.editor().to_string()) {
+                Some(editor) => config.with_edit_app(editor),
+                None => config,
+            };
 
             let date = match date {
                 None => Utc::now().naive_utc(),
                 Some(d) => chrono::naive::NaiveDateTime::parse_from_str(&d, "%Y-%m-%d %H:%M:%S")?,
             };
+            let due = due.as_deref().map(parse_due).transpose()?;
+            let status = match status {
+                Some(status) => config.check_status(&status)?,
+                None => "".to_string(),
+            };
             let editor = create_editor(&config, &exe_path);
-            let text = editor.edit(final_name.clone(), date, None)?;
+            let mut text = editor.edit(final_name.clone(), date, template)?;
+            if secret {
+                text = seal_secret(&text, &secret_passphrase(true)?)?;
+            }
             if !text.is_empty() || !&config.no_empty_adds_or_updates() {
-                repo.insert_note(Note::new(
-                    Uuid::new_v4().to_string(),
-                    selected_projjj.guid().to_owned(),
-                    final_name.clone().or_else(|| Some("".to_string())).unwrap(),
-                    text,
-                    date,
-                ))
+                repo.insert_note(
+                    Note::new(
+                        Uuid::new_v4().to_string(),
+                        selected_projjj.guid().to_owned(),
+                        final_name.clone().or_else(|| Some("".to_string())).unwrap(),
+                        text,
+                        date,
+                    )
+                    .with_author(config.author())
+                    .with_due(due)
+                    .with_status(status),
+                )
                 .await?;
             }
         }
-        CommandMode::Delete { id } => {
-            let notes = repo.list_note_with_filter(match_guid_prefix(&id)).await?;
-            check_guid_prefix_match(&notes)?;
-            let id = notes.first().unwrap().guid().to_string();
-            repo.remove_note(id).await?;
+        CommandMode::Delete { selection } => {
+            let notes = select_notes(&repo, &selection, &selected_proj).await?;
+            if confirm_selection(&notes, "Delete", &selection)? {
+                let count = repo
+                    .remove_notes(notes.iter().map(|it| it.guid().to_owned()).collect_vec())
+                    .await?;
+                println!("Deleted {count} notes");
+            }
+        }
+        CommandMode::List {
+            no_guid,
+            all,
+            project,
+            author,
+            starred,
+            recursive,
+            status,
+        } if all || !project.is_empty() => {
+            let projects = repo
+                .list_project_with_filter(|it: &Project| all || project.contains(it.name()))
+                .await?;
+            if let Some(name) = project
+                .iter()
+                .find(|name| !projects.iter().any(|it| it.name() == *name))
+            {
+                return Err(anyhow!("Project {name} does not exist"));
+            }
+            let projects = if recursive {
+                let all_projects = repo.list_project().await?;
+                let ids = with_descendants(&all_projects, projects.iter().map(|it| it.guid()));
+                all_projects
+                    .into_iter()
+                    .filter(|it| ids.contains(it.guid()))
+                    .collect_vec()
+            } else {
+                projects
+            };
+            let notes = repo
+                .list_note_with_filter(|it: &Note| {
+                    projects.iter().any(|proj| proj.guid() == it.project_id())
+                        && match_author(&author)(it)
+                        && (!starred || it.starred())
+                        && match_status(&status)(it)
+                })
+                .await?
+                .into_iter()
+                .sorted_by_key(|it| !it.pinned())
+                .collect_vec();
+            print_notes(
+                &config,
+                &notes,
+                no_guid,
+                Some(&projects),
+                &repo.list_attachment().await?,
+            );
         }
-        CommandMode::List { no_guid } => {
+        CommandMode::Recent { number, no_guid } => {
+            let projects = repo.list_project().await?;
+            let notes = repo
+                .list_note()
+                .await?
+                .into_iter()
+                .sorted_by(|a, b| b.ts().cmp(&a.ts()))
+                .take(number)
+                .collect_vec();
+            print_notes(
+                &config,
+                &notes,
+                no_guid,
+                Some(&projects),
+                &repo.list_attachment().await?,
+            );
+        }
+        CommandMode::List {
+            no_guid,
+            author,
+            starred,
+            recursive,
+            status,
+            ..
+        } => {
             println!(
                 "Project: {}",
                 if !selected_proj.is_empty() {
@@ -183,58 +896,87 @@ async fn main() -> Result<()> {
             );
 
             let selected_projj = repo
-                .list_project_with_filter(&match_name(&selected_proj.to_string()))
+                .list_project_with_filter(match_name(&selected_proj.to_string()))
                 .await?
                 .first()
                 .unwrap()
                 .to_owned();
             println!("Notes for {}", &selected_proj);
-            let notes = repo
-                .list_note_with_filter(match_project_id(selected_projj.guid()))
-                .await?;
-            if !config.group_by_date() {
-                for note in notes {
-                    println!("{}", note.get_print(&config, no_guid))
-                }
+            let projects = repo.list_project().await?;
+            let ids = if recursive {
+                with_descendants(&projects, [selected_projj.guid()])
             } else {
-                let dates = notes
-                    .iter()
-                    .map(|it| {
-                        (
-                            format!(
-                                "{}{}{}",
-                                empty_or_value(it.name().to_string(), it.name().to_string()),
-                                if it.name().is_empty() { "" } else { "|" },
-                                it.ts()
-                                    .format(if !config.include_time() {
-                                        "%Y-%m-%d"
-                                    } else {
-                                        "%Y-%m-%d %H:%M:%S"
-                                    })
-                                    .to_string(),
-                            ),
-                            format!(
-                                "{}{}{}",
-                                if no_guid { it.guid() } else { "" },
-                                if no_guid { "\n" } else { "" },
-                                it.content()
-                            ),
-                        )
-                    })
-                    .into_group_map();
-                for date in dates {
-                    println!("{}", date.0);
-                    println!("{}", date.1.join("\n================\n"));
-                }
-            }
+                vec![selected_projj.guid().to_owned()]
+            };
+            let notes = repo
+                .list_note_with_filter(|it: &Note| {
+                    ids.contains(it.project_id())
+                        && match_author(&author)(it)
+                        && (!starred || it.starred())
+                        && match_status(&status)(it)
+                })
+                .await?
+                .into_iter()
+                .sorted_by_key(|it| !it.pinned())
+                .collect_vec();
+            print_notes(
+                &config,
+                &notes,
+                no_guid,
+                recursive.then_some(projects.as_slice()),
+                &repo.list_attachment().await?,
+            );
         }
-        CommandMode::Projects => {
+        CommandMode::Projects { long } => {
             let list = repo.list_project().await?;
-            for x in list {
-                println!("{}", x.name())
+            print_project_tree(&list, None, 0, long, &config);
+        }
+        CommandMode::Project {
+            action:
+                ProjectAction::Edit {
+                    name,
+                    description,
+                    path,
+                    colour,
+                    default_name,
+                    template,
+                    editor,
+                },
+        } => {
+            let mut project = find_project(&repo, &name).await?;
+            if let Some(colour) = colour {
+                if !colour.is_empty() && Color::try_from(colour.as_str()).is_err() {
+                    return Err(anyhow!(
+                        "Unknown colour {colour}, expected a name like red, grey or dark_cyan"
+                    ));
+                }
+                project = project.with_colour(colour);
+            }
+            if let Some(description) = description {
+                project = project.with_description(description);
+            }
+            if let Some(path) = path {
+                project = project.with_path(path);
+            }
+            if let Some(default_name) = default_name {
+                project = project.with_default_name(default_name);
             }
+            repo.update_project(project).await?;
+            // Projects are shared, what runs or is read here only comes from this machine
+            let path = config::user_config_path(exe_path);
+            if let Some(template) = template {
+                config::set_project_value(&path, &name, "template", &template)?;
+            }
+            if let Some(editor) = editor {
+                config::set_project_value(&path, &name, "editor", &editor)?;
+            }
+            println!("Updated project {name}");
         }
-        CommandMode::Edit { guid } => {
+        CommandMode::Edit {
+            selection,
+            due,
+            status,
+        } => {
             println!(
                 "Project: {}",
                 if !selected_proj.is_empty() {
@@ -249,26 +991,65 @@ async fn main() -> Result<()> {
                     "No project selected please run with the \"use <proj_name>\" command first"
                 ));
             }
-            let notes = repo.list_note_with_filter(match_guid_prefix(&guid)).await?;
-            check_guid_prefix_match(&notes)?;
-            let note = notes.first().unwrap().to_owned();
-            let editor = create_editor(&config, &exe_path);
-            let text = editor.edit(
-                Some(note.name().to_string()),
-                note.ts(),
-                Some(note.content().to_string()),
-            )?;
-
-            if !text.is_empty() || !&config.no_empty_adds_or_updates() {
-                let count = repo
-                    .update_note(note.guid().to_string(), text, note.project_id().to_owned())
-                    .await?;
-                if count == 0 {
+            let notes = select_notes(&repo, &selection, &selected_proj).await?;
+            if !confirm_selection(&notes, "Edit", &selection)? {
+                return Ok(());
+            }
+            if due.is_some() || status.is_some() {
+                let due = match due.as_deref() {
+                    None => None,
+                    Some("none") => Some(None),
+                    Some(due) => Some(Some(parse_due(due)?)),
+                };
+                let status = match status.as_deref() {
+                    None => None,
+                    Some("none") => Some("".to_string()),
+                    Some(status) => Some(config.check_status(status)?),
+                };
+                let updated = notes
+                    .into_iter()
+                    .map(|it| {
+                        let due = due.unwrap_or(it.due());
+                        let status = status.clone().unwrap_or(it.status().to_string());
+                        it.with_due(due).with_status(status)
+                    })
+                    .collect_vec();
+                let expected = updated.len() as u64;
+                if repo.update_notes(updated).await? != expected {
                     return Err(anyhow!("Update failed"));
                 }
+                return Ok(());
+            }
+            let mut updated = vec![];
+            for note in notes {
+                // Secret notes are edited in the clear and locked again with the same passphrase
+                let passphrase = if note.is_secret() {
+                    Some(secret_passphrase(false)?)
+                } else {
+                    None
+                };
+                let content = match &passphrase {
+                    Some(passphrase) => open_secret(note.content(), passphrase)?,
+                    None => note.content().to_string(),
+                };
+                let editor = create_editor(&config, &exe_path);
+                let mut text =
+                    editor.edit(Some(note.name().to_string()), note.ts(), Some(content))?;
+                if !text.is_empty() || !&config.no_empty_adds_or_updates() {
+                    if let Some(passphrase) = &passphrase {
+                        text = seal_secret(&text, passphrase)?;
+                    }
+                    updated.push(note.with_content(text));
+                }
+            }
+            let expected = updated.len() as u64;
+            let count = repo.update_notes(updated).await?;
+            if count != expected {
+                return Err(anyhow!("Update failed"));
             }
         }
         CommandMode::View { guid, no_guid } => {
+            let guid = select_note_ref(&repo, guid).await?;
             println!(
                 "Project: {}",
                 if !selected_proj.is_empty() {
@@ -277,12 +1058,22 @@ async fn main() -> Result<()> {
                     "Not Selected"
                 }
             );
-            let notes = repo.list_note_with_filter(match_guid_prefix(&guid)).await?;
+            let notes = find_notes(&repo, &guid, &selected_proj).await?;
             check_guid_prefix_match(&notes)?;
-            let note = notes.first().unwrap().to_owned();
-            println!("{}", note.get_print(&config, no_guid));
+            let mut note = notes.first().unwrap().to_owned();
+            if note.is_secret() {
+                let content = open_secret(note.content(), &secret_passphrase(false)?)?;
+                note = note.with_content(content);
+            }
+            let attachments = repo
+                .list_attachment_with_filter(|it: &Attachment| it.note_id() == note.guid())
+                .await?;
+            println!(
+                "{}",
+                note.get_print(&config, no_guid, None, attachments.len())
+            );
         }
-        CommandMode::Move { guid } => {
+        CommandMode::Move { selection, target } => {
             println!(
                 "Project: {}",
                 if !selected_proj.is_empty() {
@@ -297,30 +1088,636 @@ async fn main() -> Result<()> {
                     "No project selected please run with the \"use <proj_name>\" command first"
                 ));
             }
-            let selected_projj = repo
-                .list_project_with_filter(&match_name(&selected_proj.to_string()))
+            let notes = select_notes(&repo, &selection, &selected_proj).await?;
+            if !confirm_selection(&notes, "Move", &selection)? {
+                return Ok(());
+            }
+            // The target project created on the way is kept only if the notes are moved
+            repo.begin().await?;
+            let moved = async {
+                let target_proj = find_target_project(&repo, &target, &selected_proj).await?;
+                let expected = notes.len() as u64;
+                let count = repo
+                    .update_notes(
+                        notes
+                            .into_iter()
+                            .map(|it| it.with_project_id(target_proj.guid().to_owned()))
+                            .collect_vec(),
+                    )
+                    .await?;
+                if count != expected {
+                    return Err(anyhow!("Update failed"));
+                }
+                Ok((count, target_proj))
+            }
+            .await;
+            let (count, target_proj) = finish(&repo, moved).await?;
+            println!("Moved {count} notes to {}", target_proj.name());
+        }
+        CommandMode::Sync { action } => {
+            let data_dir = config.data_dir(exe_path);
+            let sync = GitSync::new(
+                data_dir.join("sync"),
+                BlobStore::new(data_dir.join("attachments")),
+            );
+            match action {
+                SyncAction::Init { remote } => {
+                    sync.init(&remote)?;
+                    println!("Synchronizing with {remote}");
+                }
+                SyncAction::Push => sync.push(&repo).await?,
+                SyncAction::Pull => sync.pull(&repo).await?,
+            }
+        }
+        CommandMode::Db { .. } => {
+            return Err(anyhow!(
+                "The db commands are only available with the sqlite backend"
+            ))
+        }
+        CommandMode::Config { .. } => {
+            unreachable!("The config commands run before the repository is opened")
+        }
+        CommandMode::Encryption { .. } => {
+            unreachable!("The encryption commands run before the repository is unlocked")
+        }
+        CommandMode::Copy { selection, target } => {
+            let notes = select_notes(&repo, &selection, &selected_proj).await?;
+            if !confirm_selection(&notes, "Copy", &selection)? {
+                return Ok(());
+            }
+            // Like move, the target project created on the way is kept only if the notes are copied
+            repo.begin().await?;
+            let copied = async {
+                let target_proj = find_target_project(&repo, &target, &selected_proj).await?;
+                for note in &notes {
+                    repo.insert_note(
+                        Note::new(
+                            Uuid::new_v4().to_string(),
+                            target_proj.guid().to_owned(),
+                            note.name().to_string(),
+                            note.content().to_string(),
+                            note.ts(),
+                        )
+                        .with_author(note.author().to_string())
+                        .with_due(note.due()),
+                    )
+                    .await?;
+                }
+                Ok(target_proj)
+            }
+            .await;
+            let target_proj = finish(&repo, copied).await?;
+            println!("Copied {} notes to {}", notes.len(), target_proj.name());
+        }
+        CommandMode::Attach { guid, file } => {
+            let note = find_note(&repo, Some(guid), &selected_proj).await?;
+            let name = file
+                .file_name()
+                .and_then(|it| it.to_str())
+                .ok_or(anyhow!("Could not evaluate the name of {}", file.display()))?
+                .to_string();
+            if !repo
+                .list_attachment_with_filter(|it: &Attachment| {
+                    it.note_id() == note.guid() && it.name() == name
+                })
+                .await?
+                .is_empty()
+            {
+                return Err(anyhow!("The note already has an attachment named {name}"));
+            }
+            let store = BlobStore::new(config.data_dir(exe_path).join("attachments"));
+            let (hash, size) = store.put(&file)?;
+            repo.insert_attachment(Attachment::new(
+                Uuid::new_v4().to_string(),
+                note.guid().to_owned(),
+                name.clone(),
+                hash,
+                size,
+                Utc::now().naive_utc(),
+            ))
+            .await?;
+            println!("Attached {name} to {}", note.get_summary());
+        }
+        CommandMode::Attachments { guid } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            let attachments = repo
+                .list_attachment_with_filter(|it: &Attachment| it.note_id() == note.guid())
+                .await?;
+            if attachments.is_empty() {
+                println!("No attachments");
+            }
+            for attachment in attachments {
+                println!(
+                    "{} {} bytes {}",
+                    attachment.name(),
+                    attachment.size(),
+                    attachment.ts().format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+        }
+        CommandMode::Extract { guid, name, dest } => {
+            let note = find_note(&repo, Some(guid), &selected_proj).await?;
+            let attachment = repo
+                .list_attachment_with_filter(|it: &Attachment| {
+                    it.note_id() == note.guid() && it.name() == name
+                })
                 .await?
                 .first()
-                .unwrap()
+                .ok_or(anyhow!("The note has no attachment named {name}"))?
                 .to_owned();
-            let notes = repo.list_note_with_filter(match_guid_prefix(&guid)).await?;
-            check_guid_prefix_match(&notes)?;
-            let note = notes.first().unwrap();
-            let count = repo
-                .update_note(
-                    note.guid().to_string(),
-                    note.content().to_owned(),
-                    selected_projj.guid().to_owned(),
-                )
+            let dest = match dest {
+                Some(dest) if dest.is_dir() => dest.join(&name),
+                Some(dest) => dest,
+                None => PathBuf::from(&name),
+            };
+            if dest.exists() {
+                return Err(anyhow!("{} already exists", dest.display()));
+            }
+            let store = BlobStore::new(config.data_dir(exe_path).join("attachments"));
+            fs::copy(store.get(attachment.hash())?, &dest)?;
+            println!("Extracted {name} to {}", dest.display());
+        }
+        CommandMode::Links { broken: true, .. } => {
+            let projects = repo.list_project().await?;
+            let notes = repo.list_note().await?;
+            let mut count = 0;
+            for link in repo.list_link().await? {
+                if !resolve_link(&notes, link.target()).is_empty() {
+                    continue;
+                }
+                if let Some(note) = notes.iter().find(|it| it.guid() == link.note_id()) {
+                    println!("{} -> [[{}]]", note_label(note, &projects), link.target());
+                    count += 1;
+                }
+            }
+            if count > 0 {
+                return Err(anyhow!("Found {count} broken links"));
+            }
+            println!("No broken links");
+        }
+        CommandMode::Links { guid, .. } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            let projects = repo.list_project().await?;
+            let notes = repo.list_note().await?;
+            let links = repo.list_link().await?;
+            for link in links.iter().filter(|it| it.note_id() == note.guid()) {
+                let targets = resolve_link(&notes, link.target());
+                if targets.is_empty() {
+                    println!("[[{}]] broken", link.target());
+                }
+                for target in targets {
+                    println!("[[{}]] {}", link.target(), note_label(target, &projects));
+                }
+            }
+        }
+        CommandMode::Backlinks { guid } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            let projects = repo.list_project().await?;
+            let notes = repo.list_note().await?;
+            let sources = repo
+                .list_link()
+                .await?
+                .into_iter()
+                .filter(|it| {
+                    resolve_link(&notes, it.target())
+                        .iter()
+                        .any(|target| target.guid() == note.guid())
+                })
+                .map(|it| it.note_id().to_owned())
+                .collect_vec();
+            for source in notes.iter().filter(|it| sources.contains(it.guid())) {
+                println!("{}", note_label(source, &projects));
+            }
+        }
+        CommandMode::Status { guid, state } => {
+            let note = find_note(&repo, Some(guid), &selected_proj).await?;
+            let status = match state.as_str() {
+                "none" => "".to_string(),
+                state => config.check_status(state)?,
+            };
+            repo.update_notes(vec![note.clone().with_status(status)])
+                .await?;
+            println!("Set the status of {} to {state}", note.get_summary());
+        }
+        CommandMode::Pin { guid } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            repo.update_notes(vec![note.clone().with_pinned(true)])
+                .await?;
+            println!("Pinned {}", note.get_summary());
+        }
+        CommandMode::Unpin { guid } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            repo.update_notes(vec![note.clone().with_pinned(false)])
+                .await?;
+            println!("Unpinned {}", note.get_summary());
+        }
+        CommandMode::Star { guid } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            repo.update_notes(vec![note.clone().with_starred(true)])
                 .await?;
+            println!("Starred {}", note.get_summary());
+        }
+        CommandMode::Unstar { guid } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            repo.update_notes(vec![note.clone().with_starred(false)])
+                .await?;
+            println!("Unstarred {}", note.get_summary());
+        }
+        CommandMode::Due { days } => {
+            let projects = repo.list_project().await?;
+            let now = Utc::now().naive_utc();
+            let notes = repo
+                .list_note_with_filter(|it: &Note| {
+                    it.due()
+                        .is_some_and(|due| due <= now + Duration::days(days))
+                })
+                .await?
+                .into_iter()
+                .sorted_by_key(|it| it.due())
+                .collect_vec();
+            let (overdue, upcoming): (Vec<_>, Vec<_>) = notes
+                .iter()
+                .partition(|it| it.due().is_some_and(|due| due < now));
+            for (title, notes) in [("Overdue", &overdue), ("Upcoming", &upcoming)] {
+                if notes.is_empty() {
+                    continue;
+                }
+                println!("{title}:");
+                for note in notes {
+                    println!(
+                        "  {} {}",
+                        note.due().unwrap().format("%Y-%m-%d %H:%M"),
+                        note_label(note, &projects)
+                    );
+                }
+            }
+            if !overdue.is_empty() {
+                // A status for scripts and prompts, not an error to report
+                println!("{} notes are overdue", overdue.len());
+                std::process::exit(1);
+            }
+            if upcoming.is_empty() {
+                println!("Nothing due in the next {days} days");
+            }
+        }
+        CommandMode::Todo { action: None } => {
+            let project = find_project(&repo, &selected_proj).await?;
+            let notes = repo
+                .list_note_with_filter(match_project_id(project.guid()))
+                .await?;
+            let mut count = 0;
+            for note in notes.iter().sorted_by_key(|it| it.number()) {
+                for (i, (done, text)) in parse_checkboxes(note.content()).iter().enumerate() {
+                    if !done {
+                        println!("{}#{}#{} {text}", project.name(), note.number(), i + 1);
+                        count += 1;
+                    }
+                }
+            }
             if count == 0 {
-                return Err(anyhow!("Update failed"));
+                println!("No open items");
+            }
+        }
+        CommandMode::Todo {
+            action: Some(TodoAction::Done { item }),
+        } => {
+            let (reference, n) = item
+                .rsplit_once('#')
+                .filter(|(reference, _)| !reference.is_empty())
+                .ok_or(anyhow!("Expected the item as <note>#<n>, got {item}"))?;
+            let n = n
+                .parse::<usize>()
+                .map_err(|_| anyhow!("Expected the item as <note>#<n>, got {item}"))?;
+            let note = find_note(&repo, Some(reference.to_string()), &selected_proj).await?;
+            let content = tick_checkbox(note.content(), n)?;
+            repo.update_note(
+                note.guid().to_owned(),
+                content,
+                note.project_id().to_owned(),
+            )
+            .await?;
+            println!("Done {}", parse_checkboxes(note.content())[n - 1].1);
+        }
+        CommandMode::Encrypt { guid } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            if note.is_secret() {
+                return Err(anyhow!("The note is already encrypted"));
             }
+            let content = seal_secret(note.content(), &secret_passphrase(true)?)?;
+            repo.update_note(
+                note.guid().to_owned(),
+                content,
+                note.project_id().to_owned(),
+            )
+            .await?;
+            println!("Encrypted {}", note.get_summary());
+        }
+        CommandMode::Decrypt { guid } => {
+            let note = find_note(&repo, guid, &selected_proj).await?;
+            if !note.is_secret() {
+                return Err(anyhow!("The note is not encrypted"));
+            }
+            let content = open_secret(note.content(), &secret_passphrase(false)?)?;
+            repo.update_note(
+                note.guid().to_owned(),
+                content,
+                note.project_id().to_owned(),
+            )
+            .await?;
+            println!("Decrypted {}", note.get_summary());
         }
     }
     Ok(())
 }
 
+/// Print the notes one by one or grouped by date, prefixed by their project name if projects are given
+fn print_notes(
+    config: &Config,
+    notes: &[Note],
+    no_guid: bool,
+    projects: Option<&[Project]>,
+    attachments: &[Attachment],
+) {
+    let attachment_count = |note: &Note| {
+        attachments
+            .iter()
+            .filter(|it| it.note_id() == note.guid())
+            .count()
+    };
+    let project_name = |note: &Note| {
+        projects.and_then(|projects| {
+            projects
+                .iter()
+                .find(|it| it.guid() == note.project_id())
+                .map(|it| it.name().as_str())
+        })
+    };
+    if !config.group_by_date() {
+        for note in notes {
+            println!(
+                "{}",
+                note.get_print(config, no_guid, project_name(note), attachment_count(note))
+            )
+        }
+    } else {
+        let entry = |it: &Note| {
+            format!(
+                "{}#{}{}{}{}{}\n{}",
+                project_name(it).unwrap_or(""),
+                it.number(),
+                if no_guid { " " } else { "" },
+                if no_guid { it.guid() } else { "" },
+                attachment_label(attachment_count(it)),
+                it.flags_label(),
+                it.shown_content()
+            )
+        };
+        let (pinned, notes): (Vec<&Note>, Vec<&Note>) = notes.iter().partition(|it| it.pinned());
+        if !pinned.is_empty() {
+            println!("Pinned");
+            println!(
+                "{}",
+                pinned.into_iter().map(entry).join("\n================\n")
+            );
+        }
+        let dates = notes
+            .into_iter()
+            .map(|it| {
+                (
+                    format!(
+                        "{}{}{}",
+                        empty_or_value(it.name().to_string(), it.name().to_string()),
+                        if it.name().is_empty() { "" } else { "|" },
+                        it.ts().format(if !config.include_time() {
+                            "%Y-%m-%d"
+                        } else {
+                            "%Y-%m-%d %H:%M:%S"
+                        }),
+                    ),
+                    entry(it),
+                )
+            })
+            .into_group_map();
+        for date in dates {
+            println!("{}", date.0);
+            println!("{}", date.1.join("\n================\n"));
+        }
+    }
+}
+
+/// Summary of the note prefixed by the name of its project
+fn note_label(note: &Note, projects: &[Project]) -> String {
+    let project = projects
+        .iter()
+        .find(|it| it.guid() == note.project_id())
+        .map(|it| it.name().as_str())
+        .unwrap_or("");
+    format!("{project}{}", note.get_summary())
+}
+
+/// Return the given note reference or let the user pick a note if there is none
+async fn select_note_ref<R: NoteRepository + ProjectRepository>(
+    repo: &R,
+    reference: Option<String>,
+) -> Result<String> {
+    match reference {
+        Some(reference) => Ok(reference),
+        None => {
+            let projects = repo.list_project().await?;
+            let notes = repo.list_note().await?;
+            Ok(pick_note(&notes, &projects)?.guid().to_owned())
+        }
+    }
+}
+
+/// Resolve a reference to exactly one note, falling back to the picker if there is none
+async fn find_note<R: NoteRepository + ProjectRepository>(
+    repo: &R,
+    reference: Option<String>,
+    selected_proj: &String,
+) -> Result<Note> {
+    let reference = select_note_ref(repo, reference).await?;
+    let notes = find_notes(repo, &reference, selected_proj).await?;
+    check_guid_prefix_match(&notes)?;
+    Ok(notes.first().unwrap().to_owned())
+}
+
+/// Resolve the notes of a selection, falling back to the picker if it is empty
+async fn select_notes<R: NoteRepository + ProjectRepository>(
+    repo: &R,
+    selection: &NoteSelection,
+    selected_proj: &String,
+) -> Result<Vec<Note>> {
+    let mut candidates = vec![];
+    if !selection.ids.is_empty() {
+        for id in &selection.ids {
+            let notes = find_notes(repo, id, selected_proj).await?;
+            check_guid_prefix_match(&notes)?;
+            candidates.extend(notes);
+        }
+    } else if selection.is_query() {
+        let project = find_project(repo, selected_proj).await?;
+        candidates = repo
+            .list_note_with_filter(match_project_id(project.guid()))
+            .await?;
+    } else {
+        let id = select_note_ref(repo, None).await?;
+        candidates = find_notes(repo, &id, selected_proj).await?;
+    }
+    let before = parse_day(&selection.before)?;
+    let after = parse_day(&selection.after)?;
+    Ok(candidates
+        .into_iter()
+        .unique_by(|it| it.guid().to_owned())
+        .filter(match_query(
+            before,
+            after,
+            selection.name.as_deref(),
+            selection.contains.as_deref(),
+            selection.tag.as_deref(),
+        ))
+        .collect_vec())
+}
+
+/// Preview the selected notes and ask before touching more than one, returns false to stop
+fn confirm_selection(notes: &[Note], action: &str, selection: &NoteSelection) -> Result<bool> {
+    if notes.is_empty() {
+        return Err(anyhow!("No notes match the selection"));
+    }
+    if notes.len() == 1 && !selection.is_query() && !selection.dry_run {
+        return Ok(true);
+    }
+    for note in notes {
+        println!("{}", note.get_summary());
+    }
+    if selection.dry_run {
+        println!("{action} would affect {} notes", notes.len());
+        return Ok(false);
+    }
+    Ok(selection.yes || confirm(&format!("{action} {} notes?", notes.len()))?)
+}
+
+/// Find the notes matching a guid prefix, a "#number" in the selected project or a "project#number"
+async fn find_notes<R: NoteRepository + ProjectRepository>(
+    repo: &R,
+    reference: &str,
+    selected_proj: &String,
+) -> Result<Vec<Note>> {
+    let (proj_name, number) = match reference.split_once('#') {
+        Some((proj_name, number)) if !proj_name.is_empty() => (proj_name.to_string(), number),
+        Some((_, number)) => (selected_proj.to_owned(), number),
+        None => {
+            return repo
+                .list_note_with_filter(match_note_ref(reference, ""))
+                .await
+        }
+    };
+    let project = find_project(repo, &proj_name).await?;
+    repo.list_note_with_filter(match_note_ref(&format!("#{number}"), project.guid()))
+        .await
+}
+
+/// Find the project notes are moved or copied into, creating it if asked to
+async fn find_target_project<R: ProjectRepository>(
+    repo: &R,
+    target: &TargetProject,
+    selected_proj: &String,
+) -> Result<Project> {
+    let name = target.to.as_ref().unwrap_or(selected_proj);
+    let projs = repo.list_project_with_filter(match_name(name)).await?;
+    match projs.first() {
+        Some(project) => Ok(project.to_owned()),
+        None if target.create => create_project(repo, name).await,
+        None => Err(anyhow!(
+            "Project {name} does not exist, pass --create to create it"
+        )),
+    }
+}
+
+/// Create the project along with the parents it is nested in that do not exist yet
+async fn create_project<R: ProjectRepository>(repo: &R, name: &str) -> Result<Project> {
+    check_project_name(name)?;
+    let projects = repo.list_project().await?;
+    let mut parent: Option<Project> = None;
+    let mut path = String::new();
+    for segment in name.split('/') {
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str(segment);
+        let project = match projects.iter().find(|it| *it.name() == path) {
+            Some(project) => project.to_owned(),
+            None => {
+                let project = Project::new(
+                    Uuid::new_v4().to_string(),
+                    path.to_string(),
+                    Utc::now().naive_utc(),
+                )
+                .with_parent_id(parent.map(|it| it.guid().to_owned()));
+                repo.insert_project(project.clone()).await?;
+                project
+            }
+        };
+        parent = Some(project);
+    }
+    Ok(parent.unwrap())
+}
+
+/// Print the projects under the parent indented by depth, the ones without a parent at the top
+fn print_project_tree(
+    projects: &[Project],
+    parent: Option<&Project>,
+    depth: usize,
+    long: bool,
+    config: &Config,
+) {
+    let children = projects.iter().filter(|it| match parent {
+        Some(parent) => it.parent_id() == Some(parent.guid()),
+        None => it
+            .parent_id()
+            .is_none_or(|id| !projects.iter().any(|it| it.guid() == id)),
+    });
+    for project in children.sorted_by_key(|it| it.name()) {
+        let name = match parent {
+            Some(parent) => project
+                .name()
+                .strip_prefix(&format!("{}/", parent.name()))
+                .unwrap_or(project.name()),
+            None => project.name(),
+        };
+        let indent = "  ".repeat(depth);
+        let colour = Color::try_from(project.colour()).ok();
+        match colour.filter(|_| stdout().is_terminal()) {
+            Some(colour) => println!("{indent}{}", name.with(colour)),
+            None => println!("{indent}{name}"),
+        }
+        if long {
+            let settings = config.project(project.name());
+            let details = [
+                ("description", project.description()),
+                ("path", project.path()),
+                ("colour", project.colour()),
+                ("default name", project.default_name()),
+                ("template", settings.template()),
+                ("editor", settings.editor()),
+            ];
+            for (label, value) in details.iter().filter(|(_, value)| !value.is_empty()) {
+                println!("{indent}  {label}: {value}");
+            }
+        }
+        print_project_tree(projects, Some(project), depth + 1, long, config);
+    }
+}
+
+/// Find the project with the given name
+async fn find_project<R: ProjectRepository>(repo: &R, name: &String) -> Result<Project> {
+    repo.list_project_with_filter(match_name(name))
+        .await?
+        .first()
+        .cloned()
+        .ok_or(anyhow!("Project {name} does not exist"))
+}
+
 fn create_editor<K: AsRef<OsStr> + ?Sized>(config: &Config, exe_path: &K) -> Box<dyn Editor> {
     match config::string_optional(config.edit_app().to_owned()) {
         None => Box::new(InternalEditor {}),
@@ -0,0 +1,148 @@
+use std::io::stdout;
+
+use anyhow::{anyhow, Result};
+use crossterm::event::{KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::{event, ExecutableCommand};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use itertools::Itertools;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{List, ListState};
+use ratatui::Terminal;
+
+use crate::backend::{Note, Project};
+
+/// Embedded fuzzy finder to select one of many entries
+pub(crate) struct FuzzyPicker {
+    title: String,
+    items: Vec<String>,
+}
+
+impl FuzzyPicker {
+    pub fn new(title: String, items: Vec<String>) -> Self {
+        Self { title, items }
+    }
+
+    /// Indexes of the items matching the query, best match first
+    fn rank(&self, query: &str) -> Vec<usize> {
+        if query.is_empty() {
+            return (0..self.items.len()).collect_vec();
+        }
+        let matcher = SkimMatcherV2::default();
+        self.items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, item)| matcher.fuzzy_match(item, query).map(|it| (index, it)))
+            .sorted_by(|a, b| b.1.cmp(&a.1))
+            .map(|(index, _)| index)
+            .collect_vec()
+    }
+
+    /// Show the picker and return the index of the selected item
+    pub fn pick(self) -> Result<usize> {
+        if self.items.is_empty() {
+            return Err(anyhow!("Nothing to select from"));
+        }
+        stdout().execute(EnterAlternateScreen)?;
+        enable_raw_mode()?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+        terminal.clear()?;
+        let mut query = String::new();
+        let mut state = ListState::default().with_selected(Some(0));
+        let mut ranked = self.rank(&query);
+        let selected = 'main_loop: loop {
+            terminal.draw(|frame| {
+                let layout = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints(vec![Constraint::Length(1), Constraint::Min(1)])
+                    .split(frame.size());
+                frame.render_widget(format!("{}> {}", self.title, query), layout[0]);
+                let list = List::new(ranked.iter().map(|it| self.items[*it].as_str()))
+                    .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+                frame.render_stateful_widget(list, layout[1], &mut state);
+            })?;
+
+            if event::poll(std::time::Duration::from_millis(16))? {
+                if let event::Event::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    match key.code {
+                        KeyCode::Esc => break 'main_loop None,
+                        KeyCode::Char('q') if key.modifiers == KeyModifiers::CONTROL => {
+                            break 'main_loop None
+                        }
+                        KeyCode::Enter => {
+                            break 'main_loop state.selected().and_then(|it| ranked.get(it))
+                        }
+                        KeyCode::Up => {
+                            state.select(Some(state.selected().unwrap_or(0).saturating_sub(1)))
+                        }
+                        KeyCode::Down => state.select(Some(
+                            (state.selected().unwrap_or(0) + 1).min(ranked.len().max(1) - 1),
+                        )),
+                        KeyCode::Backspace => {
+                            query.pop();
+                            ranked = self.rank(&query);
+                            state.select(Some(0));
+                        }
+                        KeyCode::Char(c) => {
+                            query.push(c);
+                            ranked = self.rank(&query);
+                            state.select(Some(0));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        .copied();
+
+        stdout().execute(LeaveAlternateScreen)?;
+        disable_raw_mode()?;
+        selected.ok_or(anyhow!("Nothing selected"))
+    }
+}
+
+/// Let the user pick one of the notes, matching on name, date, content and project name
+pub(crate) fn pick_note(notes: &[Note], projects: &[Project]) -> Result<Note> {
+    let items = notes
+        .iter()
+        .map(|note| {
+            let project = projects
+                .iter()
+                .find(|it| it.guid() == note.project_id())
+                .map(|it| it.name().as_str())
+                .unwrap_or("");
+            let snippet = note
+                .shown_content()
+                .lines()
+                .join(" ")
+                .chars()
+                .take(60)
+                .join("");
+            format!(
+                "{}#{} {} {} {}",
+                project,
+                note.number(),
+                note.name(),
+                note.ts().format("%Y-%m-%d %H:%M"),
+                snippet
+            )
+        })
+        .collect_vec();
+    let index = FuzzyPicker::new("note".to_string(), items).pick()?;
+    Ok(notes[index].to_owned())
+}
+
+/// Let the user pick one of the projects by name
+pub(crate) fn pick_project(projects: &[Project]) -> Result<Project> {
+    let items = projects.iter().map(|it| it.name().to_owned()).collect_vec();
+    let index = FuzzyPicker::new("project".to_string(), items).pick()?;
+    Ok(projects[index].to_owned())
+}
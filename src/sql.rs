@@ -0,0 +1,63 @@
+//! Statements shared by the sqlite and postgres backends, both read the numbered $N placeholders
+
+use anyhow::Result;
+use sqlx::database::HasArguments;
+use sqlx::{Database, Encode, Executor, IntoArguments, Type};
+
+use crate::backend::{parse_links, Note};
+
+/// Take the next number of project $1 for note $2 unless the note is already in it,
+/// on postgres the row lock serializes the numbering between clients sharing the database
+pub(crate) const TAKE_NUMBER: &str = "update project set next_number=next_number+1 where id=$1 and not exists (select 1 from note where id=$2 and project_id=$1);";
+
+/// Update content and project of a note, a note moved to another project takes the number taken there
+pub(crate) const UPDATE_NOTE: &str = "update note set number=case when project_id=$1 then number else (select next_number-1 from project where id=$1) end,project_id=$1,content=$2,modified=$4 where id=$3;";
+
+/// Insert a note or update every field of the one with the same id, a note moved to another
+/// project takes the number taken there like with UPDATE_NOTE
+pub(crate) const INSERT_NOTE: &str = "insert into note(id,project_id,number,name,author,content,ts,due,pinned,starred,status,modified) values ($1,$2,(select next_number-1 from project where id=$2),$3,$4,$5,$6,$7,$8,$9,$10,$11) on conflict(id) do update set number=case when note.project_id=excluded.project_id then note.number else excluded.number end,project_id=excluded.project_id,name=excluded.name,author=excluded.author,content=excluded.content,ts=excluded.ts,due=excluded.due,pinned=excluded.pinned,starred=excluded.starred,status=excluded.status,modified=excluded.modified;";
+
+/// Move the counters of an older database past the highest number in use
+pub(crate) const RESET_COUNTERS: &str = "update project set next_number=(select max(number)+1 from note where note.project_id=project.id) where next_number<=(select coalesce(max(number),0) from note where note.project_id=project.id);";
+
+/// Replace the links of the note with the ones in its content
+pub(crate) async fn write_links<DB>(
+    conn: &mut DB::Connection,
+    note_id: &str,
+    content: &str,
+) -> Result<()>
+where
+    DB: Database,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> &'q str: Encode<'q, DB> + Type<DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+{
+    sqlx::query::<DB>("delete from note_link where note_id=$1;")
+        .bind(note_id)
+        .execute(&mut *conn)
+        .await?;
+    for target in parse_links(content) {
+        sqlx::query::<DB>("insert into note_link(note_id,target) values($1,$2);")
+            .bind(note_id)
+            .bind(target)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Links of the notes written before links were tracked
+pub(crate) async fn backfill_links<DB>(conn: &mut DB::Connection, notes: &[Note]) -> Result<()>
+where
+    DB: Database,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> &'q str: Encode<'q, DB> + Type<DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+{
+    for note in notes {
+        write_links::<DB>(conn, note.guid(), note.content()).await?;
+    }
+    Ok(())
+}
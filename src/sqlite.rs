@@ -1,27 +1,102 @@
 #![allow(dead_code)]
 
 use std::env;
+use std::ffi::{c_int, CStr, CString};
+use std::path::Path;
+use std::ptr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use itertools::Itertools;
-use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::{ConnectOptions, SqliteConnection};
+use chrono::Utc;
+use libsqlite3_sys::{
+    sqlite3, sqlite3_backup_finish, sqlite3_backup_init, sqlite3_backup_step, sqlite3_close,
+    sqlite3_errmsg, sqlite3_open_v2, SQLITE_DONE, SQLITE_OK, SQLITE_OPEN_CREATE,
+    SQLITE_OPEN_READONLY, SQLITE_OPEN_READWRITE,
+};
+use sqlx::error::ErrorKind;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{Connection, Sqlite, SqlitePool};
 
-use crate::backend::{Initable, Note, NoteRepository, Project, ProjectRepository};
+use crate::backend::{
+    apply_filter, describe_constraint, Attachment, AttachmentRepository, Conn, Initable, Link,
+    LinkRepository, Note, NoteRepository, Project, ProjectRepository, TransactionPool,
+    Transactional,
+};
+use crate::sql::{
+    backfill_links, write_links, INSERT_NOTE, RESET_COUNTERS, TAKE_NUMBER, UPDATE_NOTE,
+};
+
+/// Columns of the note table, the notes of a project are removed with it
+const NOTE_COLUMNS: &str = "(id nvarchar(256) primary key,project_id nvarchar(256) references project(id) on delete cascade,number integer,name nvarchar(150),author nvarchar(150) not null default '',due datetime,pinned boolean not null default false,starred boolean not null default false,status nvarchar(50) not null default '',content text,ts datetime,modified datetime not null default '1970-01-01 00:00:00')";
+
+/// Database file opened outside of sqlx for the backup API, closed on drop
+struct RawDatabase(*mut sqlite3);
+
+impl RawDatabase {
+    fn open(path: &Path, flags: c_int) -> Result<RawDatabase> {
+        let c_path = CString::new(path.to_str().ok_or(anyhow!("Could not evaluate path"))?)?;
+        let mut db = ptr::null_mut();
+        // Safety: db is closed on drop even if opening fails, as sqlite requires
+        let code = unsafe { sqlite3_open_v2(c_path.as_ptr(), &mut db, flags, ptr::null()) };
+        let database = RawDatabase(db);
+        if code != SQLITE_OK {
+            return Err(anyhow!(
+                "Could not open {}: {}",
+                path.display(),
+                error_message(database.0)
+            ));
+        }
+        Ok(database)
+    }
+}
+
+impl Drop for RawDatabase {
+    fn drop(&mut self) {
+        // Safety: the handle came from sqlite3_open_v2 and is not used after this
+        unsafe {
+            sqlite3_close(self.0);
+        }
+    }
+}
+
+fn error_message(db: *mut sqlite3) -> String {
+    // Safety: sqlite returns a valid string for any handle, null included
+    unsafe { CStr::from_ptr(sqlite3_errmsg(db)) }
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Copy the main database of source over the one of destination
+///
+/// # Safety
+/// Both handles must be open and not used by anything else during the copy
+unsafe fn copy_database(destination: *mut sqlite3, source: *mut sqlite3) -> Result<()> {
+    let backup = sqlite3_backup_init(destination, c"main".as_ptr(), source, c"main".as_ptr());
+    if backup.is_null() {
+        return Err(anyhow!("Backup failed: {}", error_message(destination)));
+    }
+    let step = sqlite3_backup_step(backup, -1);
+    let finish = sqlite3_backup_finish(backup);
+    if step != SQLITE_DONE || finish != SQLITE_OK {
+        return Err(anyhow!("Backup failed: {}", error_message(destination)));
+    }
+    Ok(())
+}
 
 pub struct SqliteRepository {
-    conn: SqliteConnection,
-    initialized: bool,
+    pool: TransactionPool<Sqlite>,
+    initialized: AtomicBool,
 }
 
 impl SqliteRepository {
-    fn conn(&self) -> &SqliteConnection {
-        &self.conn
+    fn pool(&self) -> &SqlitePool {
+        self.pool.pool()
     }
-    fn conn_mut(&mut self) -> &mut SqliteConnection {
-        &mut self.conn
+    async fn conn(&self) -> Result<Conn<'_, Sqlite>> {
+        self.pool.conn().await
     }
     pub(crate) async fn default() -> Result<SqliteRepository> {
         Self::connect(
@@ -34,149 +109,437 @@ impl SqliteRepository {
         )
         .await
     }
+    /// Open the database at the given path, creating it if missing
+    pub(crate) async fn open(path: &Path) -> Result<SqliteRepository> {
+        Self::connect(
+            path.to_str()
+                .ok_or(anyhow!("Could not evaluate path"))?
+                .to_string(),
+        )
+        .await
+    }
     async fn connect(conn_str: String) -> Result<SqliteRepository> {
-        let conn = SqliteConnectOptions::from_str(&conn_str.to_owned())?
+        // WAL lets readers go on while another process writes, the timeout waits out the writer
+        let options = SqliteConnectOptions::from_str(&conn_str.to_owned())?
             .create_if_missing(true)
-            .connect()
-            .await?;
-        let mut repo = SqliteRepository {
-            conn,
-            initialized: false,
+            .foreign_keys(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        let repo = SqliteRepository {
+            pool: TransactionPool::new(pool),
+            initialized: AtomicBool::new(false),
         };
         repo.init().await?;
         Ok(repo)
     }
+
+    /// Copy the database into a new file at path with the online backup API
+    pub(crate) async fn backup(&self, path: &Path) -> Result<()> {
+        if path.exists() {
+            return Err(anyhow!("{} already exists", path.display()));
+        }
+        let destination = RawDatabase::open(path, SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE)?;
+        let mut conn = self.pool().acquire().await?;
+        let mut handle = conn.lock_handle().await?;
+        // Safety: the connection is locked for the duration of the copy and the destination is ours
+        unsafe { copy_database(destination.0, handle.as_raw_handle().as_ptr()) }
+    }
+
+    /// Replace the content of the database with the one of the database file at path
+    pub(crate) async fn restore(&self, path: &Path) -> Result<()> {
+        let source = RawDatabase::open(path, SQLITE_OPEN_READONLY)?;
+        {
+            let mut conn = self.pool().acquire().await?;
+            let mut handle = conn.lock_handle().await?;
+            // Safety: the connection is locked for the duration of the copy and the source is ours
+            unsafe { copy_database(handle.as_raw_handle().as_ptr(), source.0)? }
+        }
+        // The backup may come from an older version
+        self.initialized.store(false, Ordering::Release);
+        self.init().await
+    }
+
+    pub(crate) async fn vacuum(&self) -> Result<()> {
+        sqlx::query("vacuum;").execute(self.pool()).await?;
+        Ok(())
+    }
+
+    /// Problems found by the integrity check and notes left without a project
+    pub(crate) async fn check(&self) -> Result<Vec<String>> {
+        let mut problems: Vec<String> = sqlx::query_as("pragma integrity_check;")
+            .fetch_all(self.pool())
+            .await?
+            .into_iter()
+            .map(|(it,): (String,)| it)
+            .filter(|it| it != "ok")
+            .collect();
+        let orphans: Vec<(String, String)> = sqlx::query_as(
+            "select id,project_id from note where project_id not in (select id from project);",
+        )
+        .fetch_all(self.pool())
+        .await?;
+        for (id, project_id) in orphans {
+            problems.push(format!("Note {id} belongs to missing project {project_id}"));
+        }
+        Ok(problems)
+    }
+
+    /// Recreate a note table created by an older version without cascading deletes,
+    /// sqlite cannot alter the constraints of an existing table
+    async fn rebuild_note_table(&self) -> Result<()> {
+        let (sql,): (String,) =
+            sqlx::query_as("select sql from sqlite_master where type='table' and name='note';")
+                .fetch_one(self.pool())
+                .await?;
+        if sql.to_lowercase().contains("on delete cascade") {
+            return Ok(());
+        }
+        // The pragma is per connection and has no effect inside a transaction
+        let mut conn = self.pool().acquire().await?;
+        sqlx::query("pragma foreign_keys=off;")
+            .execute(&mut *conn)
+            .await?;
+        let mut tx = conn.begin().await?;
+        sqlx::query(format!("create table note_rebuild{NOTE_COLUMNS};").as_str())
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("insert into note_rebuild(id,project_id,number,name,author,due,pinned,starred,status,content,ts,modified) select id,project_id,number,name,author,due,pinned,starred,status,content,ts,modified from note;")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("drop table note;").execute(&mut *tx).await?;
+        sqlx::query("alter table note_rebuild rename to note;")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        sqlx::query("pragma foreign_keys=on;")
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Add a column to a table created by an older version, returns true if it was missing
+    async fn add_column_if_missing(
+        &self,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<bool> {
+        let columns: Vec<(String,)> =
+            sqlx::query_as(format!("select name from pragma_table_info('{table}');").as_str())
+                .fetch_all(self.pool())
+                .await?;
+        if columns.iter().any(|(name,)| name == column) {
+            return Ok(false);
+        }
+        sqlx::query(format!("alter table {table} add column {column} {definition};").as_str())
+            .execute(self.pool())
+            .await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Transactional for SqliteRepository {
+    async fn begin(&self) -> Result<()> {
+        self.pool.begin().await
+    }
+
+    async fn commit(&self) -> Result<()> {
+        self.pool.commit().await
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        self.pool.rollback().await
+    }
 }
 
 #[async_trait]
 impl Initable for SqliteRepository {
     fn initialized(&self) -> bool {
-        self.initialized
+        self.initialized.load(Ordering::Acquire)
     }
 
-    async fn init(&mut self) -> Result<()> {
+    async fn init(&self) -> Result<()> {
         if !self.initialized() {
             self.create_proj_table().await?;
             self.create_note_table().await?;
+            self.create_attachment_table().await?;
+            self.create_link_table().await?;
         }
-        self.initialized = true;
+        self.initialized.store(true, Ordering::Release);
         Ok(())
     }
 }
 
 #[async_trait]
 impl NoteRepository for SqliteRepository {
-    async fn create_note_table(&mut self) -> Result<()> {
-        sqlx::query("create table if not exists note(id nvarchar(256) primary key,project_id nvarchar(256) references project(id), name nvarchar(150),content text,ts datetime);").execute(self.conn_mut()).await?;
+    async fn create_note_table(&self) -> Result<()> {
+        sqlx::query(format!("create table if not exists note{NOTE_COLUMNS};").as_str())
+            .execute(&mut *self.conn().await?)
+            .await?;
+        if self
+            .add_column_if_missing("note", "number", "integer")
+            .await?
+        {
+            // Number the notes of an older database in the order they were written
+            sqlx::query("update note set number=(select count(*) from note n where n.project_id=note.project_id and (n.ts<note.ts or (n.ts=note.ts and n.id<=note.id)));")
+                .execute(&mut *self.conn().await?)
+                .await?;
+        }
+        self.add_column_if_missing("note", "author", "nvarchar(150) not null default ''")
+            .await?;
+        self.add_column_if_missing("note", "due", "datetime")
+            .await?;
+        self.add_column_if_missing("note", "pinned", "boolean not null default false")
+            .await?;
+        self.add_column_if_missing("note", "starred", "boolean not null default false")
+            .await?;
+        self.add_column_if_missing("note", "status", "nvarchar(50) not null default ''")
+            .await?;
+        self.add_column_if_missing(
+            "note",
+            "modified",
+            "datetime not null default '1970-01-01 00:00:00'",
+        )
+        .await?;
+        self.rebuild_note_table().await?;
+        sqlx::query(RESET_COUNTERS)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        sqlx::query("create index if not exists note_project_id on note(project_id);")
+            .execute(&mut *self.conn().await?)
+            .await?;
+        sqlx::query(
+            "create unique index if not exists note_project_number on note(project_id,number);",
+        )
+        .execute(&mut *self.conn().await?)
+        .await?;
+        sqlx::query("create index if not exists note_ts on note(ts);")
+            .execute(&mut *self.conn().await?)
+            .await?;
         Ok(())
     }
 
-    async fn insert_note(&mut self, entity: Note) -> Result<()> {
-        let output = sqlx::query("update note set content=?, ts=? where id=?;")
-            .bind(entity.content().to_string())
-            .bind(entity.ts().to_string())
+    async fn insert_note(&self, entity: Note) -> Result<()> {
+        let mut conn = self.conn().await?;
+        let mut tx = conn.begin().await?;
+        sqlx::query(TAKE_NUMBER)
+            .bind(entity.project_id().to_string())
             .bind(entity.guid().to_string())
-            .execute(self.conn_mut())
+            .execute(&mut *tx)
             .await?;
-        if output.rows_affected() == 0 {
-            sqlx::query("insert into note(id,project_id,name,content,ts) values (?,?,?,?,?)")
-                .bind(entity.guid().to_string())
-                .bind(entity.project_id().to_string())
-                .bind(entity.name().to_string())
-                .bind(entity.content().to_string())
-                .bind(entity.ts().to_string())
-                .execute(self.conn_mut())
-                .await?;
-        }
+        sqlx::query(INSERT_NOTE)
+            .bind(entity.guid().to_string())
+            .bind(entity.project_id().to_string())
+            .bind(entity.name().to_string())
+            .bind(entity.author().to_string())
+            .bind(entity.content().to_string())
+            .bind(entity.ts().to_string())
+            .bind(entity.due().map(|it| it.to_string()))
+            .bind(entity.pinned())
+            .bind(entity.starred())
+            .bind(entity.status().to_string())
+            .bind(entity.modified().to_string())
+            .execute(&mut *tx)
+            .await
+            .map_err(describe_constraint)?;
+        write_links::<Sqlite>(&mut tx, entity.guid(), entity.content()).await?;
+        tx.commit().await?;
         Ok(())
     }
-    async fn remove_note(&mut self, key: String) -> Result<u32> {
+    async fn remove_note(&self, key: String) -> Result<u32> {
         let count: u32 = sqlx::query("delete from note where id=?;")
             .bind(key.to_string())
-            .execute(self.conn_mut())
+            .execute(&mut *self.conn().await?)
             .await?
             .rows_affected() as u32;
         Ok(count)
     }
 
-    async fn get_note(&mut self, key: String) -> Result<Note> {
+    async fn get_note(&self, key: String) -> Result<Note> {
         let item: Note = sqlx::query_as("select * from note where id=?;")
             .bind(key.to_string())
-            .fetch_one(self.conn_mut())
+            .fetch_one(&mut *self.conn().await?)
             .await?;
         Ok(item)
     }
 
-    async fn list_note(&mut self) -> Result<Vec<Note>> {
+    async fn list_note(&self) -> Result<Vec<Note>> {
         let items: Vec<Note> = sqlx::query_as("select * from note;")
-            .fetch_all(self.conn_mut())
+            .fetch_all(&mut *self.conn().await?)
             .await?;
         Ok(items)
     }
 
     async fn list_note_with_filter<T: Fn(&Note) -> bool + Send + Sync>(
-        &mut self,
+        &self,
         pred: T,
     ) -> Result<Vec<Note>> {
         let items: Vec<Note> = self.list_note().await?;
         Ok(apply_filter(pred, items))
     }
 
-    async fn update_note(&mut self, key: String, text: String, project_id: String) -> Result<u64> {
-        let count = sqlx::query("update note set project_id=?,content=? where id=?;")
+    async fn update_note(&self, key: String, text: String, project_id: String) -> Result<u64> {
+        let mut conn = self.conn().await?;
+        let mut tx = conn.begin().await?;
+        sqlx::query(TAKE_NUMBER)
+            .bind(&project_id)
+            .bind(&key)
+            .execute(&mut *tx)
+            .await?;
+        let count = sqlx::query(UPDATE_NOTE)
             .bind(project_id)
-            .bind(text)
+            .bind(&text)
             .bind(key.to_string())
-            .execute(self.conn_mut())
-            .await?
+            .bind(Utc::now().naive_utc().to_string())
+            .execute(&mut *tx)
+            .await
+            .map_err(describe_constraint)?
             .rows_affected();
+        if count > 0 {
+            write_links::<Sqlite>(&mut tx, &key, &text).await?;
+        }
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    async fn remove_notes(&self, keys: Vec<String>) -> Result<u32> {
+        let mut conn = self.conn().await?;
+        let mut tx = conn.begin().await?;
+        let mut count = 0;
+        for key in keys {
+            // Dropping the transaction on a missing note leaves all of them in place
+            if sqlx::query("delete from note where id=?;")
+                .bind(&key)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                == 0
+            {
+                return Err(anyhow!("Note {key} does not exist"));
+            }
+            count += 1;
+        }
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    async fn update_notes(&self, entities: Vec<Note>) -> Result<u64> {
+        let mut conn = self.conn().await?;
+        let mut tx = conn.begin().await?;
+        let mut count = 0;
+        for entity in entities {
+            sqlx::query(TAKE_NUMBER)
+                .bind(entity.project_id().to_string())
+                .bind(entity.guid().to_string())
+                .execute(&mut *tx)
+                .await?;
+            if sqlx::query(UPDATE_NOTE)
+                .bind(entity.project_id().to_string())
+                .bind(entity.content().to_string())
+                .bind(entity.guid().to_string())
+                .bind(Utc::now().naive_utc().to_string())
+                .execute(&mut *tx)
+                .await
+                .map_err(describe_constraint)?
+                .rows_affected()
+                == 0
+            {
+                return Err(anyhow!("Note {} does not exist", entity.guid()));
+            }
+            count += 1;
+            sqlx::query("update note set due=?,pinned=?,starred=?,status=? where id=?;")
+                .bind(entity.due().map(|it| it.to_string()))
+                .bind(entity.pinned())
+                .bind(entity.starred())
+                .bind(entity.status().to_string())
+                .bind(entity.guid().to_string())
+                .execute(&mut *tx)
+                .await?;
+            write_links::<Sqlite>(&mut tx, entity.guid(), entity.content()).await?;
+        }
+        tx.commit().await?;
         Ok(count)
     }
 }
 
 #[async_trait]
 impl ProjectRepository for SqliteRepository {
-    async fn create_proj_table(&mut self) -> Result<()> {
-        sqlx::query("create table if not exists project(id nvarchar(256) primary key,name nvarchar(150) unique,ts datetime);").execute(self.conn_mut()).await?;
+    async fn create_proj_table(&self) -> Result<()> {
+        sqlx::query("create table if not exists project(id nvarchar(256) primary key,name nvarchar(150) unique,ts datetime,parent_id nvarchar(256) references project(id) on delete set null,description text not null default '',path text not null default '',colour nvarchar(50) not null default '',default_name nvarchar(150) not null default '',next_number integer not null default 1);").execute(&mut *self.conn().await?).await?;
+        self.add_column_if_missing(
+            "project",
+            "parent_id",
+            "nvarchar(256) references project(id) on delete set null",
+        )
+        .await?;
+        for column in ["description", "path", "colour", "default_name"] {
+            self.add_column_if_missing("project", column, "text not null default ''")
+                .await?;
+        }
+        self.add_column_if_missing("project", "next_number", "integer not null default 1")
+            .await?;
         Ok(())
     }
 
-    async fn insert_project(&mut self, entity: Project) -> Result<()> {
-        sqlx::query("insert into project(id,name,ts) values(?,?,?);")
+    async fn insert_project(&self, entity: Project) -> Result<()> {
+        sqlx::query("insert into project(id,name,ts,parent_id,description,path,colour,default_name) values(?,?,?,?,?,?,?,?);")
             .bind(entity.guid().to_string())
             .bind(entity.name())
             .bind(entity.ts().to_string())
-            .execute(self.conn_mut())
-            .await?;
+            .bind(entity.parent_id())
+            .bind(entity.description())
+            .bind(entity.path())
+            .bind(entity.colour())
+            .bind(entity.default_name())
+            .execute(&mut *self.conn().await?)
+            .await
+            .map_err(describe_constraint)?;
         Ok(())
     }
 
-    async fn remove_project(&mut self, key: String) -> Result<u32> {
+    async fn remove_project(&self, key: String) -> Result<u32> {
         let count: u32 = sqlx::query("delete from project where id=?;")
             .bind(key.to_string())
-            .execute(self.conn_mut())
+            .execute(&mut *self.conn().await?)
             .await?
             .rows_affected() as u32;
         Ok(count)
     }
 
-    async fn get_project(&mut self, key: String) -> Result<Project> {
+    async fn update_project(&self, entity: Project) -> Result<u64> {
+        let count = sqlx::query(
+            "update project set description=?,path=?,colour=?,default_name=? where id=?;",
+        )
+        .bind(entity.description())
+        .bind(entity.path())
+        .bind(entity.colour())
+        .bind(entity.default_name())
+        .bind(entity.guid().to_string())
+        .execute(&mut *self.conn().await?)
+        .await?
+        .rows_affected();
+        Ok(count)
+    }
+
+    async fn get_project(&self, key: String) -> Result<Project> {
         let item: Project = sqlx::query_as("select * from project where id=?;")
             .bind(key.to_string())
-            .fetch_one(self.conn_mut())
+            .fetch_one(&mut *self.conn().await?)
             .await?;
         Ok(item)
     }
 
-    async fn list_project(&mut self) -> Result<Vec<Project>> {
+    async fn list_project(&self) -> Result<Vec<Project>> {
         let items: Vec<Project> = sqlx::query_as("select * from project;")
-            .fetch_all(self.conn_mut())
+            .fetch_all(&mut *self.conn().await?)
             .await?;
         Ok(items)
     }
 
     async fn list_project_with_filter<T: Fn(&Project) -> bool + Send + Sync>(
-        &mut self,
+        &self,
         pred: T,
     ) -> Result<Vec<Project>> {
         let items = self.list_project().await?;
@@ -184,9 +547,85 @@ impl ProjectRepository for SqliteRepository {
     }
 }
 
-fn apply_filter<T: Clone, K: Fn(&T) -> bool + Send + Sync>(pred: K, items: Vec<T>) -> Vec<T> {
-    items
-        .iter()
-        .filter_map(|it| if pred(it) { Some(it.to_owned()) } else { None })
-        .collect_vec()
+#[async_trait]
+impl AttachmentRepository for SqliteRepository {
+    async fn create_attachment_table(&self) -> Result<()> {
+        sqlx::query("create table if not exists attachment(id nvarchar(256) primary key,note_id nvarchar(256) references note(id) on delete cascade,name nvarchar(256),hash nvarchar(64),size integer,ts datetime);")
+            .execute(&mut *self.conn().await?)
+            .await?;
+        sqlx::query("create index if not exists attachment_note_id on attachment(note_id);")
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_attachment(&self, entity: Attachment) -> Result<()> {
+        sqlx::query("insert into attachment(id,note_id,name,hash,size,ts) values(?,?,?,?,?,?);")
+            .bind(entity.guid().to_string())
+            .bind(entity.note_id().to_string())
+            .bind(entity.name().to_string())
+            .bind(entity.hash().to_string())
+            .bind(entity.size())
+            .bind(entity.ts().to_string())
+            .execute(&mut *self.conn().await?)
+            .await
+            .map_err(|err| match err.as_database_error().map(|it| it.kind()) {
+                Some(ErrorKind::ForeignKeyViolation) => {
+                    anyhow!("Note {} does not exist", entity.note_id())
+                }
+                _ => describe_constraint(err),
+            })?;
+        Ok(())
+    }
+
+    async fn remove_attachment(&self, key: String) -> Result<u32> {
+        let count: u32 = sqlx::query("delete from attachment where id=?;")
+            .bind(key)
+            .execute(&mut *self.conn().await?)
+            .await?
+            .rows_affected() as u32;
+        Ok(count)
+    }
+
+    async fn list_attachment(&self) -> Result<Vec<Attachment>> {
+        let items: Vec<Attachment> =
+            sqlx::query_as("select * from attachment order by note_id,name;")
+                .fetch_all(&mut *self.conn().await?)
+                .await?;
+        Ok(items)
+    }
+
+    async fn list_attachment_with_filter<T: Fn(&Attachment) -> bool + Send + Sync>(
+        &self,
+        pred: T,
+    ) -> Result<Vec<Attachment>> {
+        let items = self.list_attachment().await?;
+        Ok(apply_filter(pred, items))
+    }
+}
+
+#[async_trait]
+impl LinkRepository for SqliteRepository {
+    async fn create_link_table(&self) -> Result<()> {
+        let existing: Option<(String,)> = sqlx::query_as(
+            "select name from sqlite_master where type='table' and name='note_link';",
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        sqlx::query("create table if not exists note_link(note_id nvarchar(256) references note(id) on delete cascade,target nvarchar(256),primary key(note_id,target));")
+            .execute(&mut *self.conn().await?)
+            .await?;
+        if existing.is_none() {
+            let notes = self.list_note().await?;
+            backfill_links::<Sqlite>(&mut *self.conn().await?, &notes).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_link(&self) -> Result<Vec<Link>> {
+        let items: Vec<Link> = sqlx::query_as("select * from note_link order by note_id,target;")
+            .fetch_all(&mut *self.conn().await?)
+            .await?;
+        Ok(items)
+    }
 }
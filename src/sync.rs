@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+
+use crate::attachments::BlobStore;
+use crate::backend::{
+    check_project_name, finish, Attachment, AttachmentRepository, Note, NoteRepository, Project,
+    ProjectRepository, Transactional,
+};
+use crate::files::{
+    parse_attachments, read_front_matter, write_attachments, write_front_matter, ATTACHMENTS_FILE,
+    PROJECT_FILE,
+};
+
+const BRANCH: &str = "main";
+/// Directory of the tree holding the content of the attachments
+const ATTACHMENT_DIR: &str = ".attachments";
+
+/// Projects, notes and attachments of a commit of the working tree
+#[derive(Default)]
+struct Snapshot {
+    projects: Vec<Project>,
+    notes: Vec<Note>,
+    attachments: Vec<Attachment>,
+}
+
+/// Keeps the notebook in a git working tree as one Markdown file per note, laid out like the files backend
+pub(crate) struct GitSync {
+    dir: PathBuf,
+    store: BlobStore,
+}
+
+impl GitSync {
+    pub fn new(dir: PathBuf, store: BlobStore) -> Self {
+        Self { dir, store }
+    }
+
+    fn git(&self, args: &[&str]) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.git_output(args)?)
+            .trim()
+            .to_string())
+    }
+
+    /// Output of the git command as it is, for the content of files
+    fn git_output(&self, args: &[&str]) -> Result<Vec<u8>> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.dir)
+            .args(args)
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(output.stdout)
+    }
+
+    fn check_initialized(&self) -> Result<()> {
+        if !self.dir.join(".git").exists() {
+            return Err(anyhow!(
+                "Sync is not initialized please run \"sync init <remote-path>\" first"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Create the working tree tracking the remote, its notes are merged on the first pull
+    pub fn init(&self, remote: &str) -> Result<()> {
+        if self.dir.join(".git").exists() {
+            return Err(anyhow!(
+                "Sync is already initialized in {}",
+                self.dir.display()
+            ));
+        }
+        fs::create_dir_all(&self.dir)?;
+        self.git(&["init", "--quiet", "--initial-branch", BRANCH])?;
+        self.git(&["remote", "add", "origin", remote])?;
+        self.git(&["fetch", "--quiet", "origin"])?;
+        Ok(())
+    }
+
+    fn has_commit(&self, rev: &str) -> bool {
+        self.git(&[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{rev}^{{commit}}"),
+        ])
+        .is_ok()
+    }
+
+    fn read_file(&self, rev: &str, path: &str) -> Result<String> {
+        String::from_utf8(self.git_output(&["show", &format!("{rev}:{path}")])?)
+            .map_err(|_| anyhow!("{path} of {rev} is not text"))
+    }
+
+    /// Projects, notes and attachments of the commit, laid out like the files backend,
+    /// nothing for a commit that does not exist yet
+    fn read_commit(&self, rev: &str) -> Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        if !self.has_commit(rev) {
+            return Ok(snapshot);
+        }
+        let listing = self.git(&["ls-tree", "-r", "-z", "--name-only", rev])?;
+        let files = listing
+            .split('\0')
+            .filter(|it| !it.is_empty())
+            .collect_vec();
+        let project_dirs = files
+            .iter()
+            .map(Path::new)
+            .filter(|it| it.file_name().is_some_and(|name| name == PROJECT_FILE))
+            .filter_map(|it| it.parent())
+            .collect_vec();
+        for file in &files {
+            let path = Path::new(file);
+            if path.file_name().is_some_and(|it| it == PROJECT_FILE) {
+                snapshot
+                    .projects
+                    .push(toml::from_str(&self.read_file(rev, file)?)?);
+            } else if *file == ATTACHMENTS_FILE {
+                snapshot.attachments = parse_attachments(&self.read_file(rev, file)?)?;
+            } else if path.extension().is_some_and(|it| it == "md")
+                && path.parent().is_some_and(|it| project_dirs.contains(&it))
+            {
+                snapshot.notes.push(
+                    read_front_matter(&self.read_file(rev, file)?)
+                        .map_err(|err| anyhow!("Invalid note {file}: {err}"))?,
+                );
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Merge the notes of the remote into the repository
+    ///
+    /// HEAD holds the last synced state, so each side is compared against it:
+    /// a note changed on one side only takes that change, a note changed on both sides is a conflict
+    /// that keeps the version modified last, a note removed on one side is removed if the other did
+    /// not change it.
+    pub async fn pull<
+        R: NoteRepository + ProjectRepository + AttachmentRepository + Transactional + Send,
+    >(
+        &self,
+        repo: &R,
+    ) -> Result<()> {
+        self.check_initialized()?;
+        self.git(&["fetch", "--quiet", "origin"])?;
+        let remote_ref = format!("origin/{BRANCH}");
+        if !self.has_commit(&remote_ref) {
+            return Ok(());
+        }
+        // HEAD only moves to the remote once the merge is committed, a failed merge is
+        // compared against the same base on the next pull
+        let base = self.read_commit("HEAD")?;
+        let remote = self.read_commit(&remote_ref)?;
+
+        // A failed merge is rolled back, the files backend only takes back the projects it added
+        repo.begin().await?;
+        let merged = async {
+            // Projects are matched by name, so that the same project created on two machines is one,
+            // and sorted by name so that parents are inserted before their children
+            let mut local_projects = repo.list_project().await?;
+            for project in remote.projects.iter().sorted_by_key(|it| it.name()) {
+                check_project_name(project.name())?;
+                if !local_projects.iter().any(|it| it.name() == project.name()) {
+                    let parent_id = local_projects
+                        .iter()
+                        .find(|it| Some(it.name().as_str()) == project.parent_name())
+                        .map(|it| it.guid().to_owned());
+                    let project = project.clone().with_parent_id(parent_id);
+                    repo.insert_project(project.clone()).await?;
+                    local_projects.push(project);
+                }
+            }
+            let local_projects = repo.list_project().await?;
+            let remote_notes = localize(remote.notes, &remote.projects, &local_projects);
+            let base = localize(base.notes, &base.projects, &local_projects)
+                .into_iter()
+                .map(|it| (it.guid().to_owned(), it))
+                .collect::<HashMap<_, _>>();
+            let local = repo
+                .list_note()
+                .await?
+                .into_iter()
+                .map(|it| (it.guid().to_owned(), it))
+                .collect::<HashMap<_, _>>();
+            let (mut added, mut updated, mut removed, mut conflicts) = (0, 0, 0, 0);
+            for note in &remote_notes {
+                let note = note.to_owned();
+                let base_note = base.get(note.guid());
+                match (local.get(note.guid()), base_note) {
+                    (None, Some(_)) => {}
+                    (None, None) => {
+                        repo.insert_note(note).await?;
+                        added += 1;
+                    }
+                    (Some(local_note), _) if same_note(local_note, &note) => {}
+                    (Some(local_note), base_note) => {
+                        let local_changed = base_note.is_none_or(|it| !same_note(local_note, it));
+                        let remote_changed = base_note.is_none_or(|it| !same_note(&note, it));
+                        if !remote_changed {
+                            continue;
+                        }
+                        let remote_wins = !local_changed || note.modified() > local_note.modified();
+                        if local_changed {
+                            conflicts += 1;
+                            println!(
+                                "Conflict on note {}, kept the {} version",
+                                note.guid(),
+                                if remote_wins { "remote" } else { "local" }
+                            );
+                        }
+                        if remote_wins {
+                            repo.insert_note(note.clone()).await?;
+                            updated += 1;
+                        }
+                    }
+                }
+            }
+            for (guid, base_note) in &base {
+                if remote_notes.iter().any(|it| it.guid() == guid) {
+                    continue;
+                }
+                if let Some(local_note) = local.get(guid) {
+                    if same_note(local_note, base_note) {
+                        repo.remove_note(guid.to_owned()).await?;
+                        removed += 1;
+                    }
+                }
+            }
+            // Attachments are never changed, only the new ones of notes that are still there come in
+            let local_attachments = repo.list_attachment().await?;
+            let notes = repo.list_note().await?;
+            let mut attached = 0;
+            for attachment in remote.attachments {
+                if local_attachments
+                    .iter()
+                    .any(|it| it.guid() == attachment.guid())
+                    || !notes.iter().any(|it| it.guid() == attachment.note_id())
+                {
+                    continue;
+                }
+                if self.store.get(attachment.hash()).is_err() {
+                    let content = self.git_output(&[
+                        "show",
+                        &format!("{remote_ref}:{ATTACHMENT_DIR}/{}", attachment.hash()),
+                    ])?;
+                    if self.store.put_content(&content)?.0 != attachment.hash() {
+                        return Err(anyhow!(
+                            "The content of the attachment {} does not match its hash",
+                            attachment.guid()
+                        ));
+                    }
+                }
+                repo.insert_attachment(attachment).await?;
+                attached += 1;
+            }
+            Ok((added, updated, removed, attached, conflicts))
+        }
+        .await;
+        let (added, updated, removed, attached, conflicts) = finish(repo, merged).await?;
+        self.git(&["reset", "--quiet", "--hard", &remote_ref])?;
+        println!(
+            "Pulled {added} new, {updated} updated and {removed} removed notes, {attached} attachments, {conflicts} conflicts"
+        );
+        Ok(())
+    }
+
+    /// Merge the remote, write the repository into the working tree and push it
+    pub async fn push<
+        R: NoteRepository + ProjectRepository + AttachmentRepository + Transactional + Send,
+    >(
+        &self,
+        repo: &R,
+    ) -> Result<()> {
+        self.pull(repo).await?;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.file_name().is_some_and(|it| it == ".git") {
+                continue;
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::remove_file(path)?;
+            }
+        }
+        let projects = repo.list_project().await?;
+        for project in &projects {
+            check_project_name(project.name())?;
+            let dir = self.dir.join(project.name());
+            fs::create_dir_all(&dir)?;
+            fs::write(dir.join(PROJECT_FILE), toml::to_string(project)?)?;
+        }
+        for note in repo.list_note().await? {
+            let project = projects
+                .iter()
+                .find(|it| it.guid() == note.project_id())
+                .ok_or(anyhow!("Note {} has no project", note.guid()))?;
+            write_note(&self.dir.join(project.name()), &note)?;
+        }
+        let attachments = repo.list_attachment().await?;
+        if !attachments.is_empty() {
+            self.store.copy_to(
+                &attachments.iter().map(|it| it.hash()).collect_vec(),
+                &self.dir.join(ATTACHMENT_DIR),
+            )?;
+            write_attachments(&self.dir, attachments)?;
+        }
+        self.git(&["add", "--all"])?;
+        if self.git(&["status", "--porcelain"])?.is_empty() {
+            println!("Nothing to push");
+            return Ok(());
+        }
+        self.git(&[
+            "commit",
+            "--quiet",
+            "-m",
+            &format!(
+                "devnotes sync {}",
+                chrono::Utc::now().format("%Y-%m-%d %H:%M:%S")
+            ),
+        ])?;
+        self.git(&["push", "--quiet", "origin", &format!("HEAD:{BRANCH}")])?;
+        let changed = self.git(&["show", "--name-only", "--format=", "HEAD"])?;
+        println!("Pushed {} changed files", changed.lines().count());
+        Ok(())
+    }
+}
+
+fn write_note(dir: &Path, note: &Note) -> Result<()> {
+    fs::write(
+        dir.join(format!("{}.md", note.guid())),
+        write_front_matter(note)?,
+    )?;
+    Ok(())
+}
+
+/// Whether two versions of a note carry the same data, numbers are per machine and ignored
+fn same_note(a: &Note, b: &Note) -> bool {
+    a.content() == b.content()
+        && a.name() == b.name()
+        && a.author() == b.author()
+        && a.due() == b.due()
+        && a.pinned() == b.pinned()
+        && a.starred() == b.starred()
+        && a.status() == b.status()
+        && a.ts() == b.ts()
+        && a.modified() == b.modified()
+        && a.project_id() == b.project_id()
+}
+
+/// Point the notes of a tree at the local projects with the same name
+fn localize(notes: Vec<Note>, tree_projects: &[Project], local_projects: &[Project]) -> Vec<Note> {
+    notes
+        .into_iter()
+        .map(|note| {
+            let local_id = tree_projects
+                .iter()
+                .find(|it| it.guid() == note.project_id())
+                .and_then(|tree| local_projects.iter().find(|it| it.name() == tree.name()))
+                .map(|it| it.guid().to_owned());
+            match local_id {
+                Some(local_id) => note.with_project_id(local_id),
+                None => note,
+            }
+        })
+        .collect_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::conformance::TempDir;
+    use crate::sqlite::SqliteRepository;
+
+    /// A machine with its own database and working tree
+    async fn machine(dir: &Path, remote: &Path) -> Result<(SqliteRepository, GitSync)> {
+        fs::create_dir_all(dir)?;
+        let repo = SqliteRepository::open(&dir.join("note.db")).await?;
+        let sync = GitSync::new(dir.join("sync"), BlobStore::new(dir.join("attachments")));
+        sync.init(remote.to_str().unwrap())?;
+        sync.git(&["config", "user.name", "devnotes"])?;
+        sync.git(&["config", "user.email", "devnotes@localhost"])?;
+        Ok((repo, sync))
+    }
+
+    async fn content(repo: &SqliteRepository, guid: &str) -> Result<String> {
+        Ok(repo.get_note(guid.to_owned()).await?.content().to_string())
+    }
+
+    async fn edit(repo: &SqliteRepository, note: &Note, content: &str) -> Result<()> {
+        repo.update_note(
+            note.guid().to_owned(),
+            content.to_string(),
+            note.project_id().to_owned(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> Result<()> {
+        let dir = TempDir::new()?;
+        let remote = dir.0.join("remote.git");
+        let output = Command::new("git")
+            .args(["init", "--quiet", "--bare", "--initial-branch", BRANCH])
+            .arg(&remote)
+            .output()?;
+        assert!(output.status.success());
+        let (a, a_sync) = machine(&dir.0.join("a"), &remote).await?;
+        let (b, b_sync) = machine(&dir.0.join("b"), &remote).await?;
+
+        let project = Project::new(
+            Uuid::new_v4().to_string(),
+            "api".to_string(),
+            Utc::now().naive_utc(),
+        );
+        a.insert_project(project.clone()).await?;
+        let note = Note::new(
+            Uuid::new_v4().to_string(),
+            project.guid().to_owned(),
+            "".to_string(),
+            "first".to_string(),
+            Utc::now().naive_utc(),
+        );
+        a.insert_note(note.clone()).await?;
+        a_sync.push(&a).await?;
+        b_sync.pull(&b).await?;
+        assert_eq!(content(&b, note.guid()).await?, "first");
+        let note = b.get_note(note.guid().to_owned()).await?;
+
+        // An edit on one side only is taken by the other
+        edit(&b, &note, "edited on b").await?;
+        b_sync.push(&b).await?;
+        a_sync.pull(&a).await?;
+        assert_eq!(content(&a, note.guid()).await?, "edited on b");
+
+        // Edits on both sides keep the one modified last, whichever side pulls
+        edit(&a, &note, "older on a").await?;
+        edit(&b, &note, "newer on b").await?;
+        b_sync.push(&b).await?;
+        a_sync.push(&a).await?;
+        assert_eq!(content(&a, note.guid()).await?, "newer on b");
+        edit(&b, &note, "older on b").await?;
+        edit(&a, &note, "newer on a").await?;
+        a_sync.push(&a).await?;
+        b_sync.pull(&b).await?;
+        assert_eq!(content(&b, note.guid()).await?, "newer on a");
+
+        // So does a change of the other fields
+        b.update_notes(vec![b
+            .get_note(note.guid().to_owned())
+            .await?
+            .with_pinned(true)
+            .with_status("done".to_string())])
+            .await?;
+        b_sync.push(&b).await?;
+        a_sync.pull(&a).await?;
+        let pulled = a.get_note(note.guid().to_owned()).await?;
+        assert!(pulled.pinned());
+        assert_eq!(pulled.status(), "done");
+
+        // A pull that fails leaves HEAD at the last synced state, so the next one still
+        // brings in the note instead of taking it for one removed locally
+        let file = dir.0.join("log.txt");
+        fs::write(&file, "log")?;
+        let (hash, size) = a_sync.store.put(&file)?;
+        let added = Note::new(
+            Uuid::new_v4().to_string(),
+            project.guid().to_owned(),
+            "".to_string(),
+            "added on a".to_string(),
+            Utc::now().naive_utc(),
+        );
+        a.insert_note(added.clone()).await?;
+        a.insert_attachment(Attachment::new(
+            Uuid::new_v4().to_string(),
+            added.guid().to_owned(),
+            "log.txt".to_string(),
+            hash.clone(),
+            size,
+            Utc::now().naive_utc(),
+        ))
+        .await?;
+        a_sync.push(&a).await?;
+        a_sync.git(&["rm", "--quiet", &format!("{ATTACHMENT_DIR}/{hash}")])?;
+        a_sync.git(&["commit", "--quiet", "-m", "lose the attachment"])?;
+        a_sync.git(&["push", "--quiet", "origin", &format!("HEAD:{BRANCH}")])?;
+        assert!(b_sync.pull(&b).await.is_err());
+        assert!(b.get_note(added.guid().to_owned()).await.is_err());
+        a_sync.push(&a).await?;
+        b_sync.pull(&b).await?;
+        assert_eq!(content(&b, added.guid()).await?, "added on a");
+        assert!(b_sync.store.get(&hash).is_ok());
+        b_sync.push(&b).await?;
+        a_sync.pull(&a).await?;
+        assert_eq!(content(&a, added.guid()).await?, "added on a");
+
+        // A note removed on one side goes on the other
+        a.remove_note(note.guid().to_owned()).await?;
+        a_sync.push(&a).await?;
+        b_sync.pull(&b).await?;
+        assert!(b.get_note(note.guid().to_owned()).await.is_err());
+        Ok(())
+    }
+}